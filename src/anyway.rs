@@ -1,12 +1,16 @@
+#![allow(dead_code, deprecated)]
+
 use std::error::Error;
 
+#[deprecated(note = "use `error::SnimapError` instead")]
 pub type AnyError = Box<dyn Error>;
 
+#[deprecated(note = "use `error::SnimapResult` instead")]
 pub type AnyResult<T> = Result<T, AnyError>;
 
 #[macro_export]
 macro_rules! ok {
     () => {
-        Ok::<(), $crate::anyway::AnyError>(())
+        Ok::<(), $crate::error::SnimapError>(())
     };
 }
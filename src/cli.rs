@@ -0,0 +1,211 @@
+use std::{env, path::PathBuf};
+
+use snimap::{
+    error::{SnimapError, SnimapResult},
+    logging::Verbosity,
+};
+
+pub const USAGE: &str = "\
+Usage: snimap [OPTIONS]
+
+Options:
+      --export-ca <PATH>  write the CA certificate (PEM) to PATH and exit
+      --resolve <HOSTS>   resolve comma separated HOSTS, print JSON and exit
+      --print-map         print configured hosts grouped by sni mode and exit
+      --check             request every configured host, print the results and exit
+      --generate-config   write the default config file and exit
+      --preset <GROUPS>   with --generate-config, keep only comma separated GROUPS
+      --no-tls            listen on plain http :80, skip cert generation
+      --no-hosts          leave the hosts file untouched
+      --debug-tls         log the certificate every upstream presents
+      --passthrough-unknown
+                          forward hosts missing from the config instead of 403
+      --prewarm           resolve every configured host before serving
+      --admin             serve /admin/resolver and /admin/resolve?host= (loopback only)
+      --once              exit after the first proxied request, printing its status
+      --follow-redirects <N>
+                          follow up to N redirects to configured hosts, overrides the config
+  -q, --quiet             log errors only, overrides RUST_LOG
+  -v, --verbose           log debug messages of snimap and its dependencies,
+                          overrides RUST_LOG; without it snimap logs info and up
+  -h, --help              print this help and exit";
+
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct Args {
+    pub help: bool,
+    pub export_ca: Option<PathBuf>,
+    pub resolve: Option<Vec<String>>,
+    pub print_map: bool,
+    pub check: bool,
+    pub no_tls: bool,
+    pub no_hosts: bool,
+    pub debug_tls: bool,
+    pub passthrough_unknown: bool,
+    pub prewarm: bool,
+    pub admin: bool,
+    pub once: bool,
+    pub follow_redirects: Option<usize>,
+    pub verbosity: Option<Verbosity>,
+    pub generate_config: bool,
+    pub preset: Option<Vec<String>>,
+}
+
+impl Args {
+    pub fn parse() -> SnimapResult<Self> {
+        Self::parse_from(env::args().skip(1))
+    }
+
+    pub fn parse_from<I: IntoIterator<Item = String>>(args: I) -> SnimapResult<Self> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => parsed.help = true,
+                "--print-map" => parsed.print_map = true,
+                "--check" => parsed.check = true,
+                "--no-tls" => parsed.no_tls = true,
+                "--no-hosts" => parsed.no_hosts = true,
+                "--debug-tls" => parsed.debug_tls = true,
+                "--passthrough-unknown" => parsed.passthrough_unknown = true,
+                "--prewarm" => parsed.prewarm = true,
+                "--admin" => parsed.admin = true,
+                "--once" => parsed.once = true,
+                "--generate-config" => parsed.generate_config = true,
+                "--preset" => parsed.preset = Some(split_list(&value_of(&arg, args.next())?)),
+                "-q" | "--quiet" => parsed.set_verbosity(Verbosity::Quiet)?,
+                "-v" | "--verbose" => parsed.set_verbosity(Verbosity::Verbose)?,
+                "--export-ca" => parsed.export_ca = Some(value_of(&arg, args.next())?.into()),
+                "--resolve" => parsed.resolve = Some(split_list(&value_of(&arg, args.next())?)),
+                "--follow-redirects" => {
+                    let value = value_of(&arg, args.next())?;
+                    parsed.follow_redirects = Some(value.parse().map_err(|_| {
+                        SnimapError::Args(
+                            format!("`{arg}` requires a number, got `{value}`").into(),
+                        )
+                    })?)
+                }
+                _ => return Err(SnimapError::Args(format!("unknown option `{arg}`").into())),
+            }
+        }
+        if parsed.preset.is_some() && !parsed.generate_config {
+            return Err(SnimapError::Args(
+                "`--preset` requires `--generate-config`".into(),
+            ));
+        }
+        Ok(parsed)
+    }
+
+    fn set_verbosity(&mut self, verbosity: Verbosity) -> SnimapResult<()> {
+        match self.verbosity.replace(verbosity) {
+            Some(other) if other != verbosity => Err(SnimapError::Args(
+                "`--quiet` and `--verbose` cannot be used together".into(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn value_of(arg: &str, value: Option<String>) -> SnimapResult<String> {
+    value.ok_or_else(|| SnimapError::Args(format!("`{arg}` requires a value").into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use snimap::logging::Verbosity;
+
+    use super::Args;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse_from(args.iter().map(|s| s.to_string())).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn parse_args() {
+        assert_eq!(parse(&[]), Ok(Args::default()));
+        assert_eq!(
+            parse(&["--export-ca", "ca.pem"]),
+            Ok(Args {
+                export_ca: Some("ca.pem".into()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse(&["--resolve", "a.com, b.com"]),
+            Ok(Args {
+                resolve: Some(vec!["a.com".to_string(), "b.com".to_string()]),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse(&["--no-tls", "--no-hosts", "--print-map"]),
+            Ok(Args {
+                no_tls: true,
+                no_hosts: true,
+                print_map: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse(&[
+                "--check",
+                "--debug-tls",
+                "--passthrough-unknown",
+                "--prewarm",
+                "--admin",
+                "--once"
+            ]),
+            Ok(Args {
+                check: true,
+                debug_tls: true,
+                passthrough_unknown: true,
+                prewarm: true,
+                admin: true,
+                once: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse(&["-q"]),
+            Ok(Args {
+                verbosity: Some(Verbosity::Quiet),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse(&["--verbose", "-v"]),
+            Ok(Args {
+                verbosity: Some(Verbosity::Verbose),
+                ..Default::default()
+            })
+        );
+        assert!(parse(&["--quiet", "--verbose"]).is_err());
+        assert_eq!(
+            parse(&["--generate-config", "--preset", "github,wikipedia"]),
+            Ok(Args {
+                generate_config: true,
+                preset: Some(vec!["github".to_string(), "wikipedia".to_string()]),
+                ..Default::default()
+            })
+        );
+        assert!(parse(&["--preset", "github"]).is_err());
+        assert!(parse(&["--export-ca"]).is_err());
+        assert_eq!(
+            parse(&["--follow-redirects", "5"]),
+            Ok(Args {
+                follow_redirects: Some(5),
+                ..Default::default()
+            })
+        );
+        assert!(parse(&["--follow-redirects", "many"]).is_err());
+        assert!(parse(&["--unknown"]).is_err());
+    }
+}
@@ -1,40 +1,240 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    net::IpAddr,
+};
 
+use ipnet::IpNet;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::{
+    error::{SnimapError, SnimapResult},
+    logging::PROXY,
+};
+
 type Hostname = String;
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Default)]
 pub enum Sni {
+    #[default]
     Disable,
     Override(Hostname),
+    /// candidates tried in order until one works
+    OverrideList(Vec<Hostname>),
     Remain(Hostname),
 }
 
-pub struct SniMap(HashMap<Hostname, Sni>);
+impl Sni {
+    pub fn mode_str(&self) -> &'static str {
+        match self {
+            Sni::Disable => "disable",
+            Sni::Override(_) | Sni::OverrideList(_) => "override",
+            Sni::Remain(_) => "remain",
+        }
+    }
+}
+
+impl fmt::Display for Sni {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sni::Disable => f.write_str(self.mode_str()),
+            Sni::Override(sni) | Sni::Remain(sni) => write!(f, "{}={}", self.mode_str(), sni),
+            Sni::OverrideList(snis) => write!(f, "{}={}", self.mode_str(), snis.join(",")),
+        }
+    }
+}
+
+/// (sni by hostname, upstream by hostname, group name by hostname)
+#[derive(Default)]
+pub struct SniMap(
+    HashMap<Hostname, Sni>,
+    HashMap<Hostname, Upstream>,
+    HashMap<Hostname, String>,
+);
+
+/// per hostname upstream settings, inherited from the group
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
+pub struct Upstream {
+    pub port: Option<u16>,
+    pub timeout_secs: Option<u64>,
+    pub resolve_via: Option<ResolveVia>,
+    pub user_agent: Option<UserAgent>,
+    pub headers: Option<HeaderRules>,
+    pub response: Option<ResponseRules>,
+}
+
+/// `user_agent = "ua"` replaces the client's, `user_agent = { append = "ua" }` appends to it
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
+#[serde(untagged)]
+pub enum UserAgent {
+    Replace(String),
+    Append { append: String },
+}
+
+/// `headers = { remove = ["Referer"], set = { DNT = "1" }, add = { X-Via = "snimap" } }`,
+/// applied to forwarded requests in that order
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct HeaderRules {
+    /// never sent upstream
+    pub remove: Option<Vec<String>>,
+    /// sent in place of the client's headers of the same name
+    pub set: Option<BTreeMap<String, String>>,
+    /// sent along with the client's headers of the same name
+    pub add: Option<BTreeMap<String, String>>,
+}
+
+/// `response = { rewrite_location = false, strip_hsts = true }`, applied to upstream responses
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ResponseRules {
+    pub rewrite_location: Option<bool>,
+    pub strip_hsts: Option<bool>,
+}
+
+impl ResponseRules {
+    /// point a `Location` at the sni back at the hostname, default true
+    pub fn rewrite_location(&self) -> bool {
+        self.rewrite_location.unwrap_or(true)
+    }
+
+    /// drop `Strict-Transport-Security`, default false
+    pub fn strip_hsts(&self) -> bool {
+        self.strip_hsts.unwrap_or(false)
+    }
+}
+
+impl UserAgent {
+    /// the `User-Agent` sent upstream in place of `client`
+    pub fn apply(&self, client: Option<&str>) -> String {
+        match (self, client) {
+            (UserAgent::Replace(user_agent), _) => user_agent.clone(),
+            (UserAgent::Append { append }, Some(client)) if !client.is_empty() => {
+                format!("{client} {append}")
+            }
+            (UserAgent::Append { append }, _) => append.clone(),
+        }
+    }
+}
+
+/// where the address of a hostname comes from
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolveVia {
+    /// the system resolver, which also reads the hosts file
+    System,
+    /// ipaddress.com
+    Scrape,
+}
+
+/// sorted (disabled, (overridden, snis), remain) hostnames
+type ByMode<'a> = (Vec<&'a str>, Vec<(&'a str, &'a [Hostname])>, Vec<&'a str>);
+
+/// `sni = "a.com"` or `sni = ["a.com", "b.com"]`
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
+#[serde(untagged)]
+pub enum SniValue {
+    One(Hostname),
+    List(Vec<Hostname>),
+}
+
+impl SniValue {
+    fn as_slice(&self) -> &[Hostname] {
+        match self {
+            SniValue::One(sni) => std::slice::from_ref(sni),
+            SniValue::List(snis) => snis,
+        }
+    }
+
+    fn map<F: FnMut(Hostname) -> Hostname>(self, mut f: F) -> Self {
+        match self {
+            SniValue::One(sni) => SniValue::One(f(sni)),
+            SniValue::List(snis) => SniValue::List(snis.into_iter().map(f).collect()),
+        }
+    }
+
+    fn into_vec(self) -> Vec<Hostname> {
+        match self {
+            SniValue::One(sni) => vec![sni],
+            SniValue::List(snis) => snis,
+        }
+    }
+}
+
+impl From<&str> for SniValue {
+    fn from(sni: &str) -> Self {
+        SniValue::One(sni.to_string())
+    }
+}
 
-#[derive(Deserialize, Serialize)]
+impl<const N: usize> From<[&str; N]> for SniValue {
+    fn from(snis: [&str; N]) -> Self {
+        SniValue::List(snis.into_iter().map(String::from).collect())
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug)]
 pub struct Config {
     enable: Option<bool>,
     enable_sni: Option<bool>,
+    sni_fallback: Option<bool>,
+    forwarded_for: Option<bool>,
+    decompress: Option<bool>,
+    follow_redirects: Option<usize>,
+    allowed_ips: Option<Vec<String>>,
+    http2: Option<bool>,
+    alpn: Option<Vec<String>>,
+    min_tls_version: Option<String>,
+    upstream_min_tls_version: Option<String>,
+    request_scts: Option<bool>,
+    max_body_size: Option<usize>,
+    pool_size: Option<usize>,
+    pool_idle_timeout: Option<u64>,
+    connect_timeout: Option<u64>,
+    response_timeout: Option<u64>,
+    tcp_nodelay: Option<bool>,
+    tcp_keepalive: Option<u64>,
+    remain_system_lookup: Option<bool>,
+    prefer_ipv6: Option<bool>,
+    lookup_concurrency: Option<usize>,
+    hosts_file_lookup: Option<bool>,
+    user_agent: Option<UserAgent>,
     groups: Vec<Group>,
+    overrides: Option<Vec<AddressOverride>>,
 }
 
-#[derive(Deserialize, Serialize)]
+/// pins `hostname` to `address`, bypassing every other resolution
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub struct AddressOverride {
+    hostname: String,
+    address: IpAddr,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug)]
 pub struct Group {
     enable: Option<bool>,
     enable_sni: Option<bool>,
     name: String,
-    sni: Option<String>,
+    sni: Option<SniValue>,
+    port: Option<u16>,
+    timeout_secs: Option<u64>,
+    resolve_via: Option<ResolveVia>,
+    user_agent: Option<UserAgent>,
+    headers: Option<HeaderRules>,
+    response: Option<ResponseRules>,
     mappings: Vec<Mapping>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug)]
 pub struct Mapping {
     enable: Option<bool>,
     enable_sni: Option<bool>,
     hostname: String,
-    sni: Option<String>,
+    sni: Option<SniValue>,
+    port: Option<u16>,
+    timeout_secs: Option<u64>,
+    resolve_via: Option<ResolveVia>,
+    user_agent: Option<UserAgent>,
+    headers: Option<HeaderRules>,
+    response: Option<ResponseRules>,
 }
 
 pub trait Switchable: Sized {
@@ -82,9 +282,224 @@ impl Config {
         Self {
             enable: None,
             enable_sni: None,
+            sni_fallback: None,
+            forwarded_for: None,
+            decompress: None,
+            follow_redirects: None,
+            allowed_ips: None,
+            http2: None,
+            alpn: None,
+            min_tls_version: None,
+            upstream_min_tls_version: None,
+            request_scts: None,
+            max_body_size: None,
+            pool_size: None,
+            pool_idle_timeout: None,
+            connect_timeout: None,
+            response_timeout: None,
+            tcp_nodelay: None,
+            tcp_keepalive: None,
+            remain_system_lookup: None,
+            prefer_ipv6: None,
+            lookup_concurrency: None,
+            hosts_file_lookup: None,
+            user_agent: None,
             groups,
+            overrides: None,
         }
     }
+
+    /// `Config::default()` with only the groups named in `names`, ignoring case
+    pub fn preset<S: AsRef<str>>(names: &[S]) -> SnimapResult<Self> {
+        let mut config = Config::default();
+        let is_named = |group: &Group, name: &str| group.name.eq_ignore_ascii_case(name);
+        let unknown = names
+            .iter()
+            .map(AsRef::as_ref)
+            .filter(|name| !config.groups.iter().any(|group| is_named(group, name)))
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            return Err(SnimapError::Config(
+                format!(
+                    "unknown preset {unknown:?}, choose from {}",
+                    config.group_names().join(", ")
+                )
+                .into(),
+            ));
+        }
+        config
+            .groups
+            .retain(|group| names.iter().any(|name| is_named(group, name.as_ref())));
+        Ok(config)
+    }
+
+    pub fn group_names(&self) -> Vec<&str> {
+        self.groups
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect()
+    }
+
+    /// append the groups of `other`, its other settings are ignored,
+    /// returns the names of appended groups that were already there
+    pub fn append_groups(&mut self, other: Config) -> Vec<String> {
+        let duplicates = other
+            .groups
+            .iter()
+            .filter(|group| self.groups.iter().any(|g| g.name == group.name))
+            .map(|group| group.name.clone())
+            .collect();
+        self.groups.extend(other.groups);
+        duplicates
+    }
+
+    /// returns whether a group named `name` was found
+    pub fn set_group_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        let mut found = false;
+        for group in self.groups.iter_mut().filter(|group| group.name == name) {
+            *group.enable_mut() = Some(enabled);
+            found = true;
+        }
+        found
+    }
+
+    /// sets every mapping of `hostname`, a host in a disabled group stays off,
+    /// returns whether any mapping was found
+    pub fn set_host_enabled(&mut self, hostname: &str, enabled: bool) -> bool {
+        let mut found = false;
+        let mappings = self
+            .groups
+            .iter_mut()
+            .flat_map(|group| group.mappings.iter_mut());
+        for mapping in mappings.filter(|mapping| mapping.hostname.eq_ignore_ascii_case(hostname)) {
+            *mapping.enable_mut() = Some(enabled);
+            found = true;
+        }
+        found
+    }
+
+    pub fn address_overrides(&self) -> HashMap<String, IpAddr> {
+        self.overrides
+            .iter()
+            .flatten()
+            .map(|o| (o.hostname.clone(), o.address))
+            .collect()
+    }
+
+    /// retry hosts without sni with their hostname as sni when the handshake fails, default `false`
+    pub fn sni_fallback(&self) -> bool {
+        self.sni_fallback.unwrap_or(false)
+    }
+
+    /// append `X-Forwarded-For` and `Forwarded` to upstream requests, default `false`
+    pub fn forwarded_for(&self) -> bool {
+        self.forwarded_for.unwrap_or(false)
+    }
+
+    /// decode upstream bodies the client cannot accept, default `false`
+    pub fn decompress(&self) -> bool {
+        self.decompress.unwrap_or(false)
+    }
+
+    /// redirects to configured hosts followed by the proxy itself, default `0`
+    pub fn follow_redirects(&self) -> usize {
+        self.follow_redirects.unwrap_or(0)
+    }
+
+    /// resolve hosts whose sni is kept with the system resolver before ipaddress.com, default `true`
+    pub fn remain_system_lookup(&self) -> bool {
+        self.remain_system_lookup.unwrap_or(true)
+    }
+
+    /// take an ipv6 address from the system resolver when there is one, default `false`
+    pub fn prefer_ipv6(&self) -> bool {
+        self.prefer_ipv6.unwrap_or(false)
+    }
+
+    /// how many upstream addresses may be looked up at once, default `8`
+    pub fn lookup_concurrency(&self) -> usize {
+        self.lookup_concurrency.unwrap_or(8)
+    }
+
+    /// use addresses the user pinned in the hosts file before any lookup, default `false`
+    pub fn hosts_file_lookup(&self) -> bool {
+        self.hosts_file_lookup.unwrap_or(false)
+    }
+
+    /// negotiate h2 with upstreams whose sni is not overridden, default `false`
+    pub fn http2(&self) -> bool {
+        self.http2.unwrap_or(false)
+    }
+
+    /// alpn protocols advertised to upstreams, takes precedence over `http2`
+    pub fn alpn(&self) -> Option<&[String]> {
+        self.alpn.as_deref()
+    }
+
+    /// minimum tls version ("1.2" or "1.3") accepted by the listener
+    pub fn min_tls_version(&self) -> Option<&str> {
+        self.min_tls_version.as_deref()
+    }
+
+    /// minimum tls version ("1.2" or "1.3") offered to upstreams
+    pub fn upstream_min_tls_version(&self) -> Option<&str> {
+        self.upstream_min_tls_version.as_deref()
+    }
+
+    /// ask upstreams for signed certificate timestamps, default `true`
+    pub fn request_scts(&self) -> bool {
+        self.request_scts.unwrap_or(true)
+    }
+
+    /// request body limit in bytes, `None` is unlimited
+    pub fn max_body_size(&self) -> Option<usize> {
+        self.max_body_size
+    }
+
+    /// upstream connections per client and worker, `0` is unlimited
+    pub fn pool_size(&self) -> Option<usize> {
+        self.pool_size
+    }
+
+    /// seconds before an idle upstream connection is closed
+    pub fn pool_idle_timeout(&self) -> Option<u64> {
+        self.pool_idle_timeout
+    }
+
+    /// seconds for connecting and handshaking with an upstream
+    pub fn connect_timeout(&self) -> Option<u64> {
+        self.connect_timeout
+    }
+
+    /// seconds until the upstream response head, body streaming is not limited
+    pub fn response_timeout(&self) -> Option<u64> {
+        self.response_timeout
+    }
+
+    pub fn tcp_nodelay(&self) -> Option<bool> {
+        self.tcp_nodelay
+    }
+
+    pub fn tcp_keepalive(&self) -> Option<u64> {
+        self.tcp_keepalive
+    }
+
+    /// client ip ranges allowed to use the proxy, `None` allows everyone
+    pub fn allowed_ips(&self) -> Option<Vec<IpNet>> {
+        self.allowed_ips.as_ref().map(|allowed_ips| {
+            allowed_ips
+                .iter()
+                .filter_map(|s| {
+                    s.parse::<IpNet>()
+                        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                        .inspect_err(
+                            |_| log::warn!(target: PROXY, "ignore invalid allowed_ips entry {s:?}"),
+                        )
+                        .ok()
+                })
+                .collect()
+        })
+    }
 }
 
 impl Group {
@@ -94,6 +509,12 @@ impl Group {
             enable: None,
             enable_sni: None,
             sni: None,
+            port: None,
+            timeout_secs: None,
+            resolve_via: None,
+            user_agent: None,
+            headers: None,
+            response: None,
             mappings,
         }
     }
@@ -106,63 +527,216 @@ impl Mapping {
             enable_sni: None,
             hostname: hostname.to_string(),
             sni: None,
+            port: None,
+            timeout_secs: None,
+            resolve_via: None,
+            user_agent: None,
+            headers: None,
+            response: None,
         }
     }
 
-    pub fn override_sni(mut self, sni: &str) -> Self {
-        self.sni = Some(sni.to_string());
+    pub fn override_sni<S: Into<SniValue>>(mut self, sni: S) -> Self {
+        self.sni = Some(sni.into());
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: UserAgent) -> Self {
+        self.user_agent = Some(user_agent);
+        self
+    }
+
+    pub fn headers(mut self, headers: HeaderRules) -> Self {
+        self.headers = Some(headers);
+        self
+    }
+
+    pub fn response(mut self, response: ResponseRules) -> Self {
+        self.response = Some(response);
         self
     }
 }
 
 impl SniMap {
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self(HashMap::new(), HashMap::new(), HashMap::new())
+    }
+
+    pub fn builder() -> SniMapBuilder {
+        SniMapBuilder(SniMap::new())
     }
 
     pub fn hostnames(&self) -> HashSet<&str> {
         self.0.keys().map(|s| s.as_str()).collect()
     }
 
+    /// hostnames with the name of the group they come from
+    pub fn hostnames_with_group(&self) -> HashMap<&str, Option<&str>> {
+        self.0
+            .keys()
+            .map(|hostname| (hostname.as_str(), self.group(hostname)))
+            .collect()
+    }
+
+    pub fn group(&self, hostname: &str) -> Option<&str> {
+        self.2.get(hostname).map(String::as_str)
+    }
+
     pub fn overrided_sni(&self) -> HashSet<&str> {
         self.0
             .values()
-            .filter_map(|sni| match sni {
-                Sni::Disable | Sni::Remain(_) => None,
-                Sni::Override(host) => Some(host.as_str()),
+            .flat_map(|sni| match sni {
+                Sni::Disable | Sni::Remain(_) => &[][..],
+                Sni::Override(host) => std::slice::from_ref(host),
+                Sni::OverrideList(hosts) => hosts,
             })
+            .map(String::as_str)
             .collect()
     }
 
+    pub fn by_mode(&self) -> ByMode<'_> {
+        let (mut disabled, mut overridden, mut remain) = (vec![], vec![], vec![]);
+        self.0.iter().for_each(|(hostname, sni)| match sni {
+            Sni::Disable => disabled.push(hostname.as_str()),
+            Sni::Override(sni) => overridden.push((hostname.as_str(), std::slice::from_ref(sni))),
+            Sni::OverrideList(snis) => overridden.push((hostname.as_str(), snis.as_slice())),
+            Sni::Remain(_) => remain.push(hostname.as_str()),
+        });
+        disabled.sort_unstable();
+        overridden.sort_unstable();
+        remain.sort_unstable();
+        (disabled, overridden, remain)
+    }
+
     pub fn get(&self, hostname: &str) -> Option<&Sni> {
         self.0.get(hostname)
     }
 
+    pub fn upstream(&self, hostname: &str) -> Upstream {
+        self.1.get(hostname).cloned().unwrap_or_default()
+    }
+
     pub fn insert(&mut self, k: Hostname, v: Sni) {
         self.0.insert(k, v);
     }
 
     pub fn merge<T: Into<SniMap>>(&mut self, other: T) {
-        other
-            .into()
-            .0
-            .into_iter()
-            .for_each(|(k, v)| self.insert(k, v))
+        let SniMap(snis, upstreams, groups) = other.into();
+        snis.into_iter().for_each(|(k, v)| {
+            self.2.remove(&k);
+            self.insert(k, v)
+        });
+        self.1.extend(upstreams);
+        self.2.extend(groups);
+    }
+}
+
+/// hostnames go through the same normalization and validation as `Mapping`
+pub struct SniMapBuilder(SniMap);
+
+impl SniMapBuilder {
+    pub fn disable(mut self, hostname: &str) -> Self {
+        self.0.merge(Mapping::new(hostname).disable_sni());
+        self
+    }
+
+    pub fn override_sni<S: Into<SniValue>>(mut self, hostname: &str, sni: S) -> Self {
+        self.0.merge(Mapping::new(hostname).override_sni(sni));
+        self
+    }
+
+    pub fn remain(mut self, hostname: &str) -> Self {
+        self.0.merge(Mapping::new(hostname));
+        self
+    }
+
+    pub fn build(self) -> SniMap {
+        self.0
+    }
+}
+
+/// punycode (lowercase ascii) form of `hostname`, ip literals are kept as is
+pub fn normalize_hostname(hostname: &str) -> Option<String> {
+    if hostname.parse::<IpAddr>().is_ok() {
+        return Some(hostname.to_string());
     }
+    idna::domain_to_ascii(hostname).ok()
+}
+
+/// dns name or ip literal, rejects schemes, paths, spaces and empty labels
+fn is_valid_hostname(hostname: &str) -> bool {
+    if hostname.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+    let hostname = hostname.strip_suffix('.').unwrap_or(hostname);
+    !hostname.is_empty()
+        && hostname.len() <= 253
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
 }
 
 impl From<Mapping> for SniMap {
-    fn from(dns: Mapping) -> Self {
+    fn from(mut dns: Mapping) -> Self {
         let mut snimap = SniMap::new();
+        dns.hostname =
+            normalize_hostname(&dns.hostname).unwrap_or_else(|| dns.hostname.to_ascii_lowercase());
+        dns.sni = dns.sni.map(|sni| {
+            sni.map(|sni| normalize_hostname(&sni).unwrap_or_else(|| sni.to_ascii_lowercase()))
+        });
+        if !is_valid_hostname(&dns.hostname) {
+            log::warn!(target: PROXY, "skip invalid hostname {:?}", dns.hostname);
+            return snimap;
+        }
+        if let Some(sni) = dns
+            .sni
+            .as_ref()
+            .and_then(|sni| sni.as_slice().iter().find(|sni| !is_valid_hostname(sni)))
+        {
+            log::warn!(target: PROXY, "skip {:?} with invalid sni {:?}", dns.hostname, sni);
+            return snimap;
+        }
         if dns.enabled() {
             let enable_sni = dns.enabled_sni();
-            let Mapping { hostname, sni, .. } = dns;
+            let Mapping {
+                hostname,
+                sni,
+                port,
+                timeout_secs,
+                resolve_via,
+                user_agent,
+                headers,
+                response,
+                ..
+            } = dns;
+            let upstream = Upstream {
+                port,
+                timeout_secs,
+                resolve_via,
+                user_agent,
+                headers,
+                response,
+            };
+            if upstream != Upstream::default() {
+                snimap.1.insert(hostname.clone(), upstream);
+            }
             let sni = match enable_sni {
-                true => match sni {
-                    Some(sniname) if sniname == hostname => Sni::Remain(hostname.clone()),
-                    Some(sniname) => Sni::Override(sniname),
-                    None => Sni::Remain(hostname.clone()),
-                },
+                true => {
+                    let mut snis = sni.map(SniValue::into_vec).unwrap_or_default();
+                    snis.dedup();
+                    match snis.as_slice() {
+                        [] => Sni::Remain(hostname.clone()),
+                        [sniname] if *sniname == hostname => Sni::Remain(hostname.clone()),
+                        [sniname] => Sni::Override(sniname.clone()),
+                        _ => Sni::OverrideList(snis),
+                    }
+                }
                 _ => Sni::Disable,
             };
             snimap.insert(hostname, sni)
@@ -176,8 +750,25 @@ impl From<Group> for SniMap {
         let mut snimap = SniMap::new();
         if group.enabled() {
             let enable_sni = group.enabled_sni();
-            let Group { mappings, sni, .. } = group;
+            let Group {
+                name,
+                mappings,
+                sni,
+                port,
+                timeout_secs,
+                resolve_via,
+                user_agent,
+                headers,
+                response,
+                ..
+            } = group;
             mappings.into_iter().for_each(|mut d: Mapping| {
+                d.port = d.port.or(port);
+                d.timeout_secs = d.timeout_secs.or(timeout_secs);
+                d.resolve_via = d.resolve_via.or(resolve_via);
+                d.user_agent = d.user_agent.take().or_else(|| user_agent.clone());
+                d.headers = d.headers.take().or_else(|| headers.clone());
+                d.response = d.response.or(response);
                 if enable_sni {
                     if sni.is_some() {
                         d.sni = sni.clone();
@@ -188,6 +779,11 @@ impl From<Group> for SniMap {
                 }
                 snimap.merge(d);
             });
+            snimap.2 = snimap
+                .0
+                .keys()
+                .map(|hostname| (hostname.clone(), name.clone()))
+                .collect();
         }
         snimap
     }
@@ -198,7 +794,9 @@ impl From<Config> for SniMap {
         let mut snimap = SniMap::new();
         if config.enabled() {
             let enable_sni = config.enabled_sni();
+            let user_agent = config.user_agent;
             config.groups.into_iter().for_each(|mut g: Group| {
+                g.user_agent = g.user_agent.take().or_else(|| user_agent.clone());
                 if !enable_sni {
                     g.enable_sni = Some(false);
                     g.sni = None;
@@ -305,22 +903,200 @@ impl Default for Config {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use crate::config::Sni;
 
-    use super::{Config, Group, Mapping, SniMap};
+    use super::{
+        normalize_hostname, Config, Group, HeaderRules, Mapping, ResolveVia, SniMap, SniValue,
+        Switchable, Upstream, UserAgent,
+    };
 
     #[test]
     fn config_default() {
         toml::to_string_pretty(&Config::default()).unwrap();
     }
 
+    #[test]
+    fn sni_display() {
+        assert_eq!(Sni::default(), Sni::Disable);
+        assert_eq!(Sni::Disable.to_string(), "disable");
+        assert_eq!(
+            Sni::Override("www.fanbox.cc".to_string()).to_string(),
+            "override=www.fanbox.cc"
+        );
+        assert_eq!(
+            Sni::Remain("pixiv.net".to_string()).to_string(),
+            "remain=pixiv.net"
+        );
+        assert_eq!(Sni::Remain("pixiv.net".to_string()).mode_str(), "remain");
+    }
+
+    #[test]
+    fn snimap_by_mode() {
+        let mut snimap: SniMap = Mapping::new("b.com").disable_sni().into();
+        snimap.merge(Mapping::new("a.com").disable_sni());
+        snimap.merge(Mapping::new("c.com").override_sni("front.com"));
+        snimap.merge(Mapping::new("d.com"));
+        snimap.merge(Mapping::new("e.com").override_sni("e.com"));
+        assert_eq!(
+            snimap.by_mode(),
+            (
+                vec!["a.com", "b.com"],
+                vec![("c.com", &["front.com".to_string()][..])],
+                vec!["d.com", "e.com"]
+            )
+        );
+    }
+
+    #[test]
+    fn snimap_builder() {
+        let snimap = SniMap::builder()
+            .disable("a.com")
+            .override_sni("b.com", "c.com")
+            .override_sni("e.com", ["f.com", "g.com"])
+            .remain("D.com")
+            .remain("http://invalid")
+            .build();
+        assert_eq!(snimap.get("a.com"), Some(&Sni::Disable));
+        assert_eq!(
+            snimap.get("b.com"),
+            Some(&Sni::Override("c.com".to_string()))
+        );
+        assert_eq!(
+            snimap.get("e.com"),
+            Some(&Sni::OverrideList(vec![
+                "f.com".to_string(),
+                "g.com".to_string()
+            ]))
+        );
+        assert_eq!(snimap.get("d.com"), Some(&Sni::Remain("d.com".to_string())));
+        assert_eq!(snimap.hostnames().len(), 4);
+    }
+
+    #[test]
+    fn invalid_hostname_skipped() {
+        for hostname in [
+            "",
+            "http://example.com",
+            "example.com/",
+            "exa mple.com",
+            "-example.com",
+            "example..com",
+        ] {
+            let snimap: SniMap = Mapping::new(hostname).into();
+            assert_eq!(snimap.get(hostname), None, "{hostname:?}");
+        }
+        let snimap: SniMap = Mapping::new("example.com")
+            .override_sni("https://front.com")
+            .into();
+        assert_eq!(snimap.get("example.com"), None);
+
+        for hostname in [
+            "example.com",
+            "example.com.",
+            "1.2.3.4",
+            "::1",
+            "a_b.example.com",
+        ] {
+            let snimap: SniMap = Mapping::new(hostname).into();
+            assert!(snimap.get(hostname).is_some(), "{hostname:?}");
+        }
+    }
+
+    #[test]
+    fn hostname_lowercased() {
+        let snimap: SniMap = Mapping::new("EN.Wikipedia.ORG")
+            .override_sni("Front.COM")
+            .into();
+        assert_eq!(
+            snimap.get("en.wikipedia.org"),
+            Some(&Sni::Override("front.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn sni_override_list() {
+        let config: Config = toml::from_str(
+            r#"
+[[groups]]
+name = "Pixiv"
+
+[[groups.mappings]]
+hostname = "pixiv.net"
+sni = ["www.fanbox.cc", "Fanbox.CC"]
+
+[[groups.mappings]]
+hostname = "i.pximg.net"
+sni = "s.pximg.net"
+
+[[groups.mappings]]
+hostname = "www.pixiv.net"
+sni = ["www.fanbox.cc"]
+"#,
+        )
+        .unwrap();
+        let snimap = SniMap::from(config);
+        assert_eq!(
+            snimap.get("pixiv.net"),
+            Some(&Sni::OverrideList(vec![
+                "www.fanbox.cc".to_string(),
+                "fanbox.cc".to_string()
+            ]))
+        );
+        assert_eq!(
+            snimap.get("i.pximg.net"),
+            Some(&Sni::Override("s.pximg.net".to_string()))
+        );
+        assert_eq!(
+            snimap.get("www.pixiv.net"),
+            Some(&Sni::Override("www.fanbox.cc".to_string()))
+        );
+        assert_eq!(
+            snimap.overrided_sni(),
+            ["www.fanbox.cc", "fanbox.cc", "s.pximg.net"]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            snimap.get("pixiv.net").unwrap().to_string(),
+            "override=www.fanbox.cc,fanbox.cc"
+        );
+
+        let snimap: SniMap = Mapping::new("pixiv.net")
+            .override_sni(["www.fanbox.cc", "https://bad"])
+            .into();
+        assert_eq!(snimap.get("pixiv.net"), None);
+    }
+
+    #[test]
+    fn idn_hostname_normalized() {
+        let snimap: SniMap = Mapping::new("例子.测试").override_sni("Bücher.de").into();
+        assert_eq!(
+            snimap.get("xn--fsqu00a.xn--0zwm56d"),
+            Some(&Sni::Override("xn--bcher-kva.de".to_string()))
+        );
+        assert_eq!(snimap.get("例子.测试"), None);
+        assert_eq!(
+            normalize_hostname("例子.测试").as_deref(),
+            Some("xn--fsqu00a.xn--0zwm56d")
+        );
+        assert_eq!(normalize_hostname("::1").as_deref(), Some("::1"));
+    }
+
     #[test]
     fn dns_into_snimap() {
         let snimap: SniMap = Mapping {
             enable: Some(false),
             enable_sni: Some(false),
             hostname: "hostname".to_string(),
-            sni: Some("sni".to_string()),
+            sni: Some(SniValue::One("sni".to_string())),
+            port: None,
+            timeout_secs: None,
+            resolve_via: None,
+            user_agent: None,
+            headers: None,
+            response: None,
         }
         .into();
         assert_eq!(snimap.0.len(), 0, "1");
@@ -331,7 +1107,13 @@ mod tests {
             enable: Some(true),
             enable_sni: Some(false),
             hostname: "hostname".to_string(),
-            sni: Some("sni".to_string()),
+            sni: Some(SniValue::One("sni".to_string())),
+            port: None,
+            timeout_secs: None,
+            resolve_via: None,
+            user_agent: None,
+            headers: None,
+            response: None,
         }
         .into();
         assert_eq!(snimap.get("hostname"), Some(&Sni::Disable));
@@ -340,7 +1122,13 @@ mod tests {
             enable: Some(true),
             enable_sni: Some(true),
             hostname: "hostname".to_string(),
-            sni: Some("sni".to_string()),
+            sni: Some(SniValue::One("sni".to_string())),
+            port: None,
+            timeout_secs: None,
+            resolve_via: None,
+            user_agent: None,
+            headers: None,
+            response: None,
         }
         .into();
         assert_eq!(
@@ -353,6 +1141,12 @@ mod tests {
             enable_sni: Some(true),
             hostname: "hostname".to_string(),
             sni: None,
+            port: None,
+            timeout_secs: None,
+            resolve_via: None,
+            user_agent: None,
+            headers: None,
+            response: None,
         }
         .into();
         assert_eq!(
@@ -367,37 +1161,322 @@ mod tests {
             enable: Some(true),
             enable_sni: Some(false),
             name: "name".to_string(),
-            sni: Some("group_sni".to_string()),
+            sni: Some(SniValue::One("group_sni".to_string())),
+            port: None,
+            timeout_secs: None,
+            resolve_via: None,
+            user_agent: None,
+            headers: None,
+            response: None,
             mappings: vec![Mapping {
                 enable: Some(true),
                 enable_sni: Some(true),
                 hostname: "hostname".to_string(),
-                sni: Some("sni".to_string()),
+                sni: Some(SniValue::One("sni".to_string())),
+                port: None,
+                timeout_secs: None,
+                resolve_via: None,
+                user_agent: None,
+                headers: None,
+                response: None,
             }],
         }
         .into();
         assert_eq!(snimap.get("hostname"), Some(&Sni::Disable));
     }
 
+    #[test]
+    fn group_upstream_inheritance() {
+        let snimap: SniMap = Group {
+            enable: None,
+            enable_sni: None,
+            name: "name".to_string(),
+            sni: None,
+            port: Some(8443),
+            timeout_secs: Some(10),
+            resolve_via: None,
+            user_agent: None,
+            headers: None,
+            response: None,
+            mappings: vec![
+                Mapping::new("inherit"),
+                Mapping {
+                    port: Some(9443),
+                    ..Mapping::new("port")
+                },
+                Mapping {
+                    timeout_secs: Some(60),
+                    resolve_via: None,
+                    ..Mapping::new("timeout")
+                },
+            ],
+        }
+        .into();
+        assert_eq!(
+            snimap.upstream("inherit"),
+            Upstream {
+                port: Some(8443),
+                timeout_secs: Some(10),
+                resolve_via: None,
+                user_agent: None,
+                headers: None,
+                response: None
+            }
+        );
+        assert_eq!(
+            snimap.upstream("port"),
+            Upstream {
+                port: Some(9443),
+                timeout_secs: Some(10),
+                resolve_via: None,
+                user_agent: None,
+                headers: None,
+                response: None
+            }
+        );
+        assert_eq!(
+            snimap.upstream("timeout"),
+            Upstream {
+                port: Some(8443),
+                timeout_secs: Some(60),
+                resolve_via: None,
+                user_agent: None,
+                headers: None,
+                response: None
+            }
+        );
+
+        let snimap: SniMap = Group {
+            resolve_via: Some(ResolveVia::System),
+            ..Group::new(
+                "name",
+                vec![
+                    Mapping::new("inherit"),
+                    Mapping {
+                        resolve_via: Some(ResolveVia::Scrape),
+                        ..Mapping::new("scrape")
+                    },
+                ],
+            )
+        }
+        .into();
+        assert_eq!(
+            snimap.upstream("inherit").resolve_via,
+            Some(ResolveVia::System)
+        );
+        assert_eq!(
+            snimap.upstream("scrape").resolve_via,
+            Some(ResolveVia::Scrape)
+        );
+
+        let snimap: SniMap = Group::new("name", vec![Mapping::new("hostname")]).into();
+        assert_eq!(snimap.upstream("hostname"), Upstream::default());
+        assert_eq!(snimap.upstream("unknown"), Upstream::default());
+    }
+
+    #[test]
+    fn user_agent_inheritance() {
+        let config: Config = toml::from_str(
+            r#"
+            user_agent = "global"
+
+            [[groups]]
+            name = "default"
+            mappings = [{ hostname = "default.com" }]
+
+            [[groups]]
+            name = "group"
+            user_agent = { append = "group" }
+            mappings = [
+                { hostname = "group.com" },
+                { hostname = "mapping.com", user_agent = "mapping" },
+            ]
+            "#,
+        )
+        .unwrap();
+        toml::to_string_pretty(&config).unwrap();
+        let snimap: SniMap = config.into();
+        let user_agent = |hostname| snimap.upstream(hostname).user_agent;
+        assert_eq!(
+            user_agent("default.com"),
+            Some(UserAgent::Replace("global".to_string()))
+        );
+        assert_eq!(
+            user_agent("group.com"),
+            Some(UserAgent::Append {
+                append: "group".to_string()
+            })
+        );
+        assert_eq!(
+            user_agent("mapping.com"),
+            Some(UserAgent::Replace("mapping".to_string()))
+        );
+    }
+
+    #[test]
+    fn header_rules_inheritance() {
+        let config: Config = toml::from_str(
+            r#"
+            [[groups]]
+            name = "group"
+            headers = { remove = ["Referer"], add = { X-Via = "snimap" } }
+            mappings = [
+                { hostname = "group.com" },
+                { hostname = "mapping.com", headers = { set = { DNT = "1" } } },
+            ]
+            "#,
+        )
+        .unwrap();
+        toml::to_string_pretty(&config).unwrap();
+        let snimap: SniMap = config.into();
+        assert_eq!(
+            snimap.upstream("group.com").headers,
+            Some(HeaderRules {
+                remove: Some(vec!["Referer".to_string()]),
+                add: Some([("X-Via".to_string(), "snimap".to_string())].into()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            snimap.upstream("mapping.com").headers,
+            Some(HeaderRules {
+                set: Some([("DNT".to_string(), "1".to_string())].into()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn user_agent_apply() {
+        let replace = UserAgent::Replace("snimap".to_string());
+        assert_eq!(replace.apply(Some("curl/7.0")), "snimap");
+        assert_eq!(replace.apply(None), "snimap");
+        let append = UserAgent::Append {
+            append: "snimap".to_string(),
+        };
+        assert_eq!(append.apply(Some("curl/7.0")), "curl/7.0 snimap");
+        assert_eq!(append.apply(Some("")), "snimap");
+        assert_eq!(append.apply(None), "snimap");
+    }
+
+    #[test]
+    fn snimap_group_name() {
+        let mut snimap: SniMap = Config::new(vec![
+            Group::new("A", vec![Mapping::new("a.com"), Mapping::new("moved.com")]),
+            Group::new("B", vec![Mapping::new("b.com")]),
+        ])
+        .into();
+        snimap.merge(Mapping::new("moved.com"));
+        assert_eq!(snimap.group("a.com"), Some("A"));
+        assert_eq!(snimap.group("b.com"), Some("B"));
+        assert_eq!(snimap.group("moved.com"), None);
+        assert_eq!(
+            snimap.hostnames_with_group(),
+            HashMap::from([
+                ("a.com", Some("A")),
+                ("b.com", Some("B")),
+                ("moved.com", None)
+            ])
+        );
+    }
+
     #[test]
     fn config_into_config_map() {
         let snimap: SniMap = Config {
             enable: Some(true),
             enable_sni: Some(true),
+            sni_fallback: None,
+            forwarded_for: None,
+            decompress: None,
+            follow_redirects: None,
+            allowed_ips: None,
+            http2: None,
+            alpn: None,
+            min_tls_version: None,
+            upstream_min_tls_version: None,
+            request_scts: None,
+            max_body_size: None,
+            pool_size: None,
+            pool_idle_timeout: None,
+            connect_timeout: None,
+            response_timeout: None,
+            tcp_nodelay: None,
+            tcp_keepalive: None,
+            remain_system_lookup: None,
+            prefer_ipv6: None,
+            lookup_concurrency: None,
+            hosts_file_lookup: None,
+            user_agent: None,
             groups: vec![Group {
                 enable: Some(true),
                 enable_sni: Some(false),
                 name: "name".to_string(),
-                sni: Some("group_sni".to_string()),
+                sni: Some(SniValue::One("group_sni".to_string())),
+                port: None,
+                timeout_secs: None,
+                resolve_via: None,
+                user_agent: None,
+                headers: None,
+                response: None,
                 mappings: vec![Mapping {
                     enable: Some(true),
                     enable_sni: Some(true),
                     hostname: "hostname".to_string(),
-                    sni: Some("sni".to_string()),
+                    sni: Some(SniValue::One("sni".to_string())),
+                    port: None,
+                    timeout_secs: None,
+                    resolve_via: None,
+                    user_agent: None,
+                    headers: None,
+                    response: None,
                 }],
             }],
+            overrides: None,
         }
         .into();
         assert_eq!(snimap.get("hostname"), Some(&Sni::Disable));
     }
+
+    #[test]
+    fn config_preset() {
+        let config = Config::preset(&["github", "WIKIPEDIA"]).unwrap();
+        assert_eq!(config.group_names(), ["Github", "Wikipedia"]);
+        assert_eq!(config.enable, Config::default().enable);
+
+        let error = Config::preset(&["github", "gitlab"])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("\"gitlab\""), "{error}");
+        assert!(Config::preset::<&str>(&[])
+            .unwrap()
+            .group_names()
+            .is_empty());
+    }
+
+    #[test]
+    fn toggle_enabled() {
+        let mut config = Config::new(vec![
+            Group::new("Pixiv", vec![Mapping::new("pixiv.net")]),
+            Group::new(
+                "Duckduckgo",
+                vec![Mapping::new("duck.com"), Mapping::new("duckduckgo.com")],
+            ),
+        ]);
+
+        assert!(config.set_group_enabled("Pixiv", false));
+        assert_eq!(config.groups[0].enable, Some(false));
+        assert_eq!(config.groups[1].enable, None);
+
+        assert!(config.set_host_enabled("Duck.com", false));
+        assert_eq!(config.groups[1].mappings[0].enable, Some(false));
+        assert_eq!(config.groups[1].mappings[1].enable, None);
+
+        assert!(!config.set_group_enabled("Wikipedia", false));
+        assert!(!config.set_host_enabled("zh.wikipedia.org", false));
+
+        let snimap = SniMap::from(config);
+        assert_eq!(snimap.get("pixiv.net"), None);
+        assert_eq!(snimap.get("duck.com"), None);
+        assert!(snimap.get("duckduckgo.com").is_some());
+    }
 }
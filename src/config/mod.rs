@@ -1,7 +1,13 @@
-use toml::{de::Error as TomlDeError, ser::Error as TomlSerError};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
 
-use crate::anyway::AnyResult;
 use crate::dirs;
+use crate::error::{SnimapError, SnimapResult};
+use crate::logging::PROXY;
 use crate::utils::{create_dir_all, read_to_string, write};
 
 pub use self::format::*;
@@ -9,24 +15,371 @@ pub use self::format::*;
 mod format;
 
 impl Config {
-    pub async fn from_default_file() -> AnyResult<Config> {
+    /// the config as written, `${NAME}` is left for `expand_env`
+    pub async fn from_default_file() -> SnimapResult<Config> {
         let config_file = dirs::config_file();
         let config = if config_file.is_file() {
-            parse(read_to_string(&config_file)?.as_bytes())?
+            Config::from_file(&config_file)?
         } else {
-            create_dir_all(&dirs::config_dir())?;
             let default_config = Config::default();
-            write(&config_file, &stringify(&default_config)?)?;
+            default_config.create_default_file()?;
             default_config
         };
-        Ok(config)
+        config.merge_dir(&dirs::config_d_dir())
+    }
+
+    /// write `self` to the default config file, which must not exist yet
+    pub fn create_default_file(&self) -> SnimapResult<PathBuf> {
+        let config_file = dirs::config_file();
+        if config_file.exists() {
+            return Err(SnimapError::Config(
+                format!("{} already exists", config_file.display()).into(),
+            ));
+        }
+        create_dir_all(&dirs::config_dir()).map_err(|e| SnimapError::Config(e.into()))?;
+        self.save(&config_file)?;
+        Ok(config_file)
+    }
+
+    pub fn from_file(path: &PathBuf) -> SnimapResult<Config> {
+        let content = read_to_string(path).map_err(|e| SnimapError::Config(e.into()))?;
+        parse(content.as_bytes(), Format::of(path))
+    }
+
+    /// a copy with `${NAME}` in every string replaced by the environment variable,
+    /// to build the `SniMap` and options from, `self` stays what gets saved
+    pub fn expand_env(&self) -> SnimapResult<Config> {
+        expand_env(self, |name| env::var(name).ok())
+    }
+
+    /// append the groups of every `*.toml` and `*.json` file in `dir`, in file name order
+    pub fn merge_dir(mut self, dir: &Path) -> SnimapResult<Config> {
+        if !dir.is_dir() {
+            return Ok(self);
+        }
+        let mut paths = fs::read_dir(dir)
+            .map_err(|e| SnimapError::Config(e.into()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.is_file()
+                    && matches!(
+                        path.extension().and_then(|e| e.to_str()),
+                        Some("toml" | "json")
+                    )
+            })
+            .collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            let config = Config::from_file(&path)
+                .map_err(|e| SnimapError::Config(format!("{}: {e}", path.display()).into()))?;
+            for name in self.append_groups(config) {
+                log::warn!(target: PROXY, "group {name:?} in {path:?} is already defined");
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn save(&self, path: &PathBuf) -> SnimapResult<()> {
+        write(path, &stringify(self, Format::of(path))?).map_err(|e| SnimapError::Config(e.into()))
+    }
+}
+
+/// picked by file extension, anything but `.json` is toml
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    fn of(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Format::Json,
+            _ => Format::Toml,
+        }
     }
 }
 
-fn parse(slice: &[u8]) -> Result<Config, TomlDeError> {
-    toml::from_slice(slice)
+fn parse(slice: &[u8], format: Format) -> SnimapResult<Config> {
+    Ok(match format {
+        Format::Toml => toml::from_slice(slice)?,
+        Format::Json => serde_json::from_slice(slice)?,
+    })
 }
 
-fn stringify(config: &Config) -> Result<String, TomlSerError> {
-    toml::to_string(config)
+fn stringify(config: &Config, format: Format) -> SnimapResult<String> {
+    Ok(match format {
+        Format::Toml => toml::to_string(config)?,
+        Format::Json => serde_json::to_string_pretty(config)?,
+    })
+}
+
+/// replace `${NAME}` in every string value with `var(NAME)`
+fn expand_env(config: &Config, var: impl Fn(&str) -> Option<String>) -> SnimapResult<Config> {
+    let mut value = serde_json::to_value(config)?;
+    expand_value(&mut value, &var).map_err(|e| SnimapError::Config(e.into()))?;
+    Ok(serde_json::from_value(value)?)
+}
+
+fn expand_value(value: &mut Value, var: &impl Fn(&str) -> Option<String>) -> Result<(), String> {
+    match value {
+        Value::String(s) => *s = expand_vars(s, var)?,
+        Value::Array(values) => values.iter_mut().try_for_each(|v| expand_value(v, var))?,
+        Value::Object(map) => map.values_mut().try_for_each(|v| expand_value(v, var))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_vars(s: &str, var: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let (mut expanded, mut rest) = (String::with_capacity(s.len()), s);
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let len = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated `${{` in {s:?}"))?;
+        let name = &rest[start + 2..start + len];
+        expanded.push_str(
+            &var(name).ok_or_else(|| format!("environment variable `{name}` is not set"))?,
+        );
+        rest = &rest[start + len + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{expand_env, parse, stringify, Config, Format, Group, Mapping, Sni, SniMap};
+
+    fn round_trip_as(config: &Config, format: Format) {
+        let text = stringify(config, format).unwrap();
+        let reparsed = parse(text.as_bytes(), format).unwrap();
+        assert_eq!(&reparsed, config);
+        assert_eq!(stringify(&reparsed, format).unwrap(), text);
+    }
+
+    fn round_trip(config: &Config) {
+        round_trip_as(config, Format::Toml);
+    }
+
+    #[test]
+    fn default_config_round_trip() {
+        round_trip(&Config::default());
+    }
+
+    #[test]
+    fn default_config_json_round_trip() {
+        round_trip_as(&Config::default(), Format::Json);
+        let json = stringify(&Config::default(), Format::Json).unwrap();
+        let toml = stringify(&Config::default(), Format::Toml).unwrap();
+        assert_eq!(
+            parse(json.as_bytes(), Format::Json).unwrap(),
+            parse(toml.as_bytes(), Format::Toml).unwrap()
+        );
+        assert!(parse(toml.as_bytes(), Format::Json).is_err());
+    }
+
+    #[test]
+    fn expand_env_vars() {
+        let config = parse(
+            br#"
+[[groups]]
+name = "${GROUP}"
+sni = "${SNI_PREFIX}.fanbox.cc"
+
+[[groups.mappings]]
+hostname = "pixiv.net"
+"#,
+            Format::Toml,
+        )
+        .unwrap();
+        let var = |name: &str| match name {
+            "GROUP" => Some("Pixiv".to_string()),
+            "SNI_PREFIX" => Some("www".to_string()),
+            _ => None,
+        };
+        let expected = parse(
+            br#"
+[[groups]]
+name = "Pixiv"
+sni = "www.fanbox.cc"
+
+[[groups.mappings]]
+hostname = "pixiv.net"
+"#,
+            Format::Toml,
+        )
+        .unwrap();
+        assert_eq!(expand_env(&config, var).unwrap(), expected);
+
+        let undefined = parse(
+            br#"
+[[groups]]
+name = "${UNDEFINED}"
+mappings = []
+"#,
+            Format::Toml,
+        )
+        .unwrap();
+        let error = expand_env(&undefined, var).unwrap_err().to_string();
+        assert!(error.contains("`UNDEFINED` is not set"), "{error}");
+
+        let unterminated = parse(
+            b"[[groups]]\nname = \"${GROUP\"\nmappings = []",
+            Format::Toml,
+        )
+        .unwrap();
+        assert!(expand_env(&unterminated, var).is_err());
+    }
+
+    #[test]
+    fn save_keeps_env_placeholders() {
+        use std::fs;
+
+        let path = std::env::temp_dir().join("snimap_test_save_keeps_env_placeholders.toml");
+        fs::write(
+            &path,
+            "[[groups]]\nname = \"Pixiv\"\nsni = \"${SNIMAP_TEST_SNI}\"\n\n[[groups.mappings]]\nhostname = \"pixiv.net\"\n",
+        )
+        .unwrap();
+        std::env::set_var("SNIMAP_TEST_SNI", "www.fanbox.cc");
+
+        let mut config = Config::from_file(&path).unwrap();
+        assert_eq!(
+            SniMap::from(config.expand_env().unwrap()).get("pixiv.net"),
+            Some(&Sni::Override("www.fanbox.cc".to_string()))
+        );
+        assert!(config.set_host_enabled("pixiv.net", false));
+        config.save(&path).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(saved.contains("${SNIMAP_TEST_SNI}"), "{saved}");
+        assert!(!saved.contains("www.fanbox.cc"), "{saved}");
+    }
+
+    #[test]
+    fn toggle_and_save() {
+        let path = std::env::temp_dir().join("snimap_test_toggle_and_save.toml");
+        let mut config = Config::default();
+        assert!(config.set_group_enabled("Pixiv", false));
+        config.save(&path).unwrap();
+        let reloaded = Config::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded, config);
+        assert_eq!(SniMap::from(reloaded).get("pixiv.net"), None);
+    }
+
+    #[test]
+    fn merge_config_dir() {
+        use std::fs;
+
+        let dir = std::env::temp_dir().join("snimap_test_merge_config_dir");
+        fs::create_dir_all(&dir).unwrap();
+        let files = [
+            (
+                "20-pixiv.toml",
+                "[[groups]]\nname = \"Pixiv\"\nsni = \"www.fanbox.cc\"\n\n[[groups.mappings]]\nhostname = \"pixiv.net\"\n",
+            ),
+            (
+                "10-duckduckgo.json",
+                r#"{"groups": [{"name": "Duckduckgo", "mappings": [{"hostname": "duck.com"}]}]}"#,
+            ),
+            ("notes.txt", "not a config"),
+        ];
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+
+        let base = Config::new(vec![Group::new(
+            "Duckduckgo",
+            vec![Mapping::new("duckduckgo.com")],
+        )]);
+        let merged = base.merge_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let snimap = SniMap::from(merged.unwrap());
+        let mut hostnames = snimap.hostnames().into_iter().collect::<Vec<_>>();
+        hostnames.sort_unstable();
+        assert_eq!(hostnames, ["duck.com", "duckduckgo.com", "pixiv.net"]);
+        assert_eq!(snimap.group("pixiv.net"), Some("Pixiv"));
+        assert_eq!(
+            snimap.get("pixiv.net"),
+            Some(&Sni::Override("www.fanbox.cc".to_string()))
+        );
+
+        let mut config = Config::new(vec![Group::new("Pixiv", vec![])]);
+        let duplicates = config.append_groups(Config::new(vec![
+            Group::new("Pixiv", vec![]),
+            Group::new("Wikipedia", vec![]),
+        ]));
+        assert_eq!(duplicates, ["Pixiv"]);
+    }
+
+    #[test]
+    fn format_of_path() {
+        assert_eq!(Format::of(Path::new("config.toml")), Format::Toml);
+        assert_eq!(Format::of(Path::new("config.JSON")), Format::Json);
+        assert_eq!(Format::of(Path::new("config")), Format::Toml);
+    }
+
+    #[test]
+    fn user_config_round_trip() {
+        let config = parse(
+            br#"
+enable = true
+enable_sni = true
+
+[[groups]]
+name = "Wikipedia"
+enable_sni = false
+
+[[groups.mappings]]
+hostname = "zh.wikipedia.org"
+
+[[groups]]
+name = "Duckduckgo"
+enable = false
+
+[[groups.mappings]]
+hostname = "duckduckgo.com"
+enable = true
+
+[[groups]]
+name = "Pixiv"
+sni = "www.fanbox.cc"
+
+[[groups.mappings]]
+hostname = "pixiv.net"
+
+[[groups.mappings]]
+hostname = "i.pximg.net"
+sni = "s.pximg.net"
+"#,
+            Format::Toml,
+        )
+        .unwrap();
+        round_trip(&config);
+        round_trip_as(&config, Format::Json);
+        let toml = stringify(&config, Format::Toml).unwrap();
+        let positions = ["Wikipedia", "Duckduckgo", "Pixiv"].map(|name| toml.find(name).unwrap());
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn save_and_reload() {
+        for name in [
+            "snimap_test_save_and_reload.toml",
+            "snimap_test_save_and_reload.json",
+        ] {
+            let path = std::env::temp_dir().join(name);
+            let config = Config::default();
+            config.save(&path).unwrap();
+            let reloaded = Config::from_file(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(reloaded, config);
+        }
+    }
 }
@@ -6,8 +6,23 @@ pub fn config_dir() -> PathBuf {
         .expect("config directory not found")
 }
 
+/// extra `*.toml` or `*.json` files whose groups are appended to the config
+pub fn config_d_dir() -> PathBuf {
+    config_dir().join("config.d")
+}
+
+/// `config.toml`, or `config.json` when only that one exists
 pub fn config_file() -> PathBuf {
-    config_dir().join("config.toml")
+    let config_dir = config_dir();
+    let (toml, json) = (
+        config_dir.join("config.toml"),
+        config_dir.join("config.json"),
+    );
+    if !toml.is_file() && json.is_file() {
+        json
+    } else {
+        toml
+    }
 }
 
 pub fn hosts_path() -> Option<PathBuf> {
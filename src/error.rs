@@ -0,0 +1,88 @@
+use std::error::Error;
+
+use actix_web::{
+    http::{
+        header::{InvalidHeaderName, InvalidHeaderValue, ToStrError},
+        uri::InvalidUri,
+        StatusCode,
+    },
+    ResponseError,
+};
+use awc::error::SendRequestError;
+use rcgen::RcgenError;
+use thiserror::Error;
+use toml::{de::Error as TomlDeError, ser::Error as TomlSerError};
+
+pub type BoxError = Box<dyn Error>;
+
+pub type SnimapResult<T> = Result<T, SnimapError>;
+
+#[derive(Debug, Error)]
+pub enum SnimapError {
+    #[error("config: {0}")]
+    Config(#[source] BoxError),
+    #[error("cert: {0}")]
+    Cert(#[source] BoxError),
+    #[error("bind: {0}")]
+    Bind(#[source] BoxError),
+    #[error("hosts: {0}")]
+    Hosts(#[source] BoxError),
+    #[error("resolve: {0}")]
+    Resolve(#[source] BoxError),
+    #[error("forward: {0}")]
+    Forward(#[source] BoxError),
+    #[error("args: {0}")]
+    Args(#[source] BoxError),
+}
+
+macro_rules! impl_from {
+    ($($v:ident: $($t:ty),*;)*) => {
+        $($(impl From<$t> for SnimapError {
+            fn from(e: $t) -> Self {
+                SnimapError::$v(e.into())
+            }
+        })*)*
+    };
+}
+impl_from!(
+    Config: TomlDeError, TomlSerError, serde_json::Error;
+    Cert: RcgenError, rustls::Error;
+    Resolve: attohttpc::Error, std::net::AddrParseError;
+    Forward: SendRequestError, InvalidUri, InvalidHeaderName, InvalidHeaderValue, ToStrError;
+);
+
+impl ResponseError for SnimapError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SnimapError::Forward(e) => match e.downcast_ref::<SendRequestError>() {
+                Some(SendRequestError::Timeout) => StatusCode::GATEWAY_TIMEOUT,
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, ResponseError};
+    use awc::error::SendRequestError;
+
+    use super::SnimapError;
+
+    #[test]
+    fn forward_error_status() {
+        assert_eq!(
+            SnimapError::from(SendRequestError::Timeout).status_code(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(
+            SnimapError::Forward("upstream closed".into()).status_code(),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            SnimapError::Hosts("hosts file not found".into()).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -1,54 +1,72 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    io,
+    net::{IpAddr, SocketAddr},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use crate::{
-    anyway::AnyResult,
-    config::{Sni, SniMap},
-    resolver::SniMapResolver,
+    config::{normalize_hostname, Config, HeaderRules, Sni, SniMap, Upstream},
+    error::{SnimapError, SnimapResult},
+    logging::{FORWARD, PROXY, SNI},
+    resolver::{SniMapResolver, UpstreamResolver},
+};
+use actix_tls::connect::{
+    ConnectError, ConnectInfo, Connection, Connector as ActixTlsConnector, Resolver,
 };
-use actix_tls::connect::{Connector as ActixTlsConnector, Resolver};
 use actix_web::{
-    dev::RequestHead,
-    http::{header, uri::PathAndQuery, Uri, Version},
-    web::{Data, Payload},
-    HttpRequest, HttpResponse,
+    body::to_bytes,
+    dev::{forward_ready, Decompress, RequestHead, Service},
+    error::PayloadError,
+    guard::{self, GuardContext},
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        uri::PathAndQuery,
+        Method, StatusCode, Uri, Version,
+    },
+    rt::net::TcpStream,
+    web::{self, Bytes, Data, Payload},
+    HttpRequest, HttpResponse, ResponseError, Route,
 };
-use awc::{Client as AwcClient, Connector as AwcConnector};
+use awc::{error::SendRequestError, Client as AwcClient, Connector as AwcConnector};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::LocalBoxFuture,
+    stream, Stream, StreamExt,
+};
+use ipnet::IpNet;
 use rustls::ClientConfig;
+use socket2::{SockRef, TcpKeepalive};
 
-/// (enable_sni, disable_sni)
-pub struct ClientPair(AwcClient, AwcClient);
+/// (enable_sni, disable_sni, override_sni, the resolver they share)
+pub struct ClientPair(AwcClient, AwcClient, AwcClient, Box<dyn UpstreamResolver>);
 
 impl ClientPair {
-    pub fn new(
+    pub fn new<R: UpstreamResolver + Clone + 'static>(
         client_config_enable_sni: Arc<ClientConfig>,
         client_config_disable_sni: Arc<ClientConfig>,
-        snimap_resolver: SniMapResolver,
+        snimap_resolver: R,
+        options: ClientOptions,
     ) -> Self {
-        let client_enable_sni = AwcClient::builder()
-            .timeout(Duration::from_secs(30))
-            .connector(
-                AwcConnector::new()
-                    .connector(
-                        ActixTlsConnector::new(Resolver::custom(snimap_resolver.clone())).service(),
-                    )
-                    .timeout(Duration::from_secs(30))
-                    .rustls(client_config_enable_sni),
-            )
-            .disable_redirects()
-            .finish();
-
-        let client_disable_sni = AwcClient::builder()
-            .timeout(Duration::from_secs(30))
-            .connector(
-                AwcConnector::new()
-                    .connector(ActixTlsConnector::new(Resolver::custom(snimap_resolver)).service())
-                    .timeout(Duration::from_secs(30))
-                    .rustls(client_config_disable_sni),
-            )
-            .disable_redirects()
-            .finish();
+        // h2 takes `:authority` from the uri, which carries the overridden sni
+        let client_config_override_sni = {
+            let mut client_config = ClientConfig::clone(&client_config_enable_sni);
+            client_config.alpn_protocols.retain(|p| p != b"h2");
+            Arc::new(client_config)
+        };
 
-        Self(client_enable_sni, client_disable_sni)
+        Self(
+            build_client(client_config_enable_sni, snimap_resolver.clone(), options),
+            build_client(client_config_disable_sni, snimap_resolver.clone(), options),
+            build_client(client_config_override_sni, snimap_resolver.clone(), options),
+            Box::new(snimap_resolver),
+        )
     }
 
     pub fn client_enable_sni(&self) -> &AwcClient {
@@ -58,75 +76,981 @@ impl ClientPair {
     pub fn client_disable_sni(&self) -> &AwcClient {
         &self.1
     }
+
+    pub fn client_override_sni(&self) -> &AwcClient {
+        &self.2
+    }
+
+    pub fn resolver(&self) -> &dyn UpstreamResolver {
+        &*self.3
+    }
+}
+
+/// upstream connection pool and timeouts of each client, per worker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// simultaneous connections, `0` is unlimited
+    pub pool_size: usize,
+    /// idle connections are closed after this
+    pub pool_idle_timeout: Duration,
+    /// tcp connect and tls handshake
+    pub connect_timeout: Duration,
+    /// until the response head arrives, the body may stream for as long as it takes
+    pub response_timeout: Duration,
+    /// send small writes right away instead of batching them
+    pub tcp_nodelay: bool,
+    /// idle time before tcp keepalive probes, `None` keeps the os default
+    pub tcp_keepalive: Option<Duration>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            pool_size: 100,
+            pool_idle_timeout: Duration::from_secs(15),
+            connect_timeout: Duration::from_secs(30),
+            response_timeout: Duration::from_secs(30),
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+        }
+    }
+}
+
+impl From<&Config> for ClientOptions {
+    fn from(config: &Config) -> Self {
+        let default = Self::default();
+        Self {
+            pool_size: config.pool_size().unwrap_or(default.pool_size),
+            pool_idle_timeout: config
+                .pool_idle_timeout()
+                .map_or(default.pool_idle_timeout, Duration::from_secs),
+            connect_timeout: config
+                .connect_timeout()
+                .map_or(default.connect_timeout, Duration::from_secs),
+            response_timeout: config
+                .response_timeout()
+                .map_or(default.response_timeout, Duration::from_secs),
+            tcp_nodelay: config.tcp_nodelay().unwrap_or(default.tcp_nodelay),
+            tcp_keepalive: config.tcp_keepalive().map(Duration::from_secs),
+        }
+    }
+}
+
+fn build_client(
+    client_config: Arc<ClientConfig>,
+    snimap_resolver: impl UpstreamResolver + 'static,
+    options: ClientOptions,
+) -> AwcClient {
+    // awc stops the request timeout once the response head is received,
+    // so long downloads are never cut off
+    AwcClient::builder()
+        .timeout(options.response_timeout)
+        .connector(
+            AwcConnector::new()
+                .connector(TcpTuned {
+                    connector: ActixTlsConnector::new(Resolver::custom(snimap_resolver)).service(),
+                    nodelay: options.tcp_nodelay,
+                    keepalive: options.tcp_keepalive,
+                })
+                .timeout(options.connect_timeout)
+                .limit(options.pool_size)
+                .conn_keep_alive(options.pool_idle_timeout)
+                .rustls(client_config),
+        )
+        .disable_redirects()
+        .finish()
+}
+
+/// applies the tcp options to each upstream socket once it is connected
+#[derive(Clone)]
+struct TcpTuned<S> {
+    connector: S,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+}
+
+impl<S> Service<ConnectInfo<Uri>> for TcpTuned<S>
+where
+    S: Service<ConnectInfo<Uri>, Response = Connection<Uri, TcpStream>, Error = ConnectError>,
+    S::Future: 'static,
+{
+    type Response = Connection<Uri, TcpStream>;
+    type Error = ConnectError;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(connector);
+
+    fn call(&self, req: ConnectInfo<Uri>) -> Self::Future {
+        let (connecting, nodelay, keepalive) =
+            (self.connector.call(req), self.nodelay, self.keepalive);
+        Box::pin(async move {
+            let connection = connecting.await?;
+            if let Err(e) = set_tcp_options(connection.io_ref(), nodelay, keepalive) {
+                log::warn!(target: FORWARD, "failed to set tcp options: {e}");
+            }
+            Ok(connection)
+        })
+    }
+}
+
+fn set_tcp_options(
+    stream: &TcpStream,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+) -> std::io::Result<()> {
+    stream.set_nodelay(nodelay)?;
+    if let Some(time) = keepalive {
+        SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct ForwardOptions {
+    pub forwarded_for: bool,
+    pub decompress: bool,
+    pub allowed_ips: Option<Vec<IpNet>>,
+    pub max_body_size: Option<usize>,
+    /// forward hosts not in the map with their own sni instead of refusing them
+    pub passthrough_unknown: bool,
+    /// retry `Sni::Disable` hosts with their hostname as sni when the handshake fails
+    pub sni_fallback: bool,
+    /// follow up to this many redirects to hosts in the map, `0` hands them to the client
+    pub follow_redirects: usize,
+}
+
+impl ForwardOptions {
+    pub fn is_allowed(&self, ip: Option<IpAddr>) -> bool {
+        match (&self.allowed_ips, ip) {
+            (None, _) => true,
+            (Some(allowed_ips), Some(ip)) => allowed_ips.iter().any(|net| net.contains(&ip)),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+impl From<&Config> for ForwardOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            forwarded_for: config.forwarded_for(),
+            decompress: config.decompress(),
+            allowed_ips: config.allowed_ips(),
+            max_body_size: config.max_body_size(),
+            passthrough_unknown: false,
+            sni_fallback: config.sni_fallback(),
+            follow_redirects: config.follow_redirects(),
+        }
+    }
+}
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// the client's `X-Request-Id` if it is usable, otherwise a new one
+fn request_id(headers: &HeaderMap) -> HeaderValue {
+    static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+    headers
+        .get(X_REQUEST_ID)
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.to_str().is_ok())
+        .cloned()
+        .unwrap_or_else(|| {
+            let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+            HeaderValue::from_str(&format!("{id:016x}")).expect("hex is a valid header value")
+        })
+}
+
+/// chain `ip` onto `X-Forwarded-For` and `Forwarded` (RFC 7239)
+fn append_forwarded_for(headers: &mut HeaderMap, ip: IpAddr) -> SnimapResult<()> {
+    let forwarded_node = match ip {
+        IpAddr::V4(ip) => format!("for={ip}"),
+        IpAddr::V6(ip) => format!("for=\"[{ip}]\""),
+    };
+    for (name, value) in [
+        (X_FORWARDED_FOR, ip.to_string()),
+        (header::FORWARDED, forwarded_node),
+    ] {
+        // every line the client sent, minus values that are not visible ASCII
+        let value = headers
+            .get_all(&name)
+            .filter_map(|prev| prev.to_str().ok())
+            .chain([value.as_str()])
+            .collect::<Vec<_>>()
+            .join(", ");
+        headers.insert(name, HeaderValue::from_str(&value)?);
+    }
+    Ok(())
+}
+
+/// `rules` of the group of a host, on top of the client's headers
+fn apply_header_rules(headers: &mut HeaderMap, rules: &HeaderRules) -> SnimapResult<()> {
+    for name in rules.remove.iter().flatten() {
+        headers.remove(HeaderName::try_from(name.as_str())?);
+    }
+    for (name, value) in rules.set.iter().flatten() {
+        headers.insert(name.as_str().try_into()?, value.as_str().try_into()?);
+    }
+    for (name, value) in rules.add.iter().flatten() {
+        headers.append(name.as_str().try_into()?, value.as_str().try_into()?);
+    }
+    Ok(())
+}
+
+/// `location` moved from `sni` back to `host`, `None` if it points anywhere else
+fn rewrite_location(location: &str, sni: &str, host: &str) -> Option<String> {
+    let uri = location.parse::<Uri>().ok()?;
+    let authority = uri.authority()?;
+    if sni.eq_ignore_ascii_case(host) || !authority.host().eq_ignore_ascii_case(sni) {
+        return None;
+    }
+    Some(format!(
+        "{}://{}{}{}",
+        uri.scheme_str().unwrap_or("https"),
+        host,
+        authority
+            .port_u16()
+            .map(|port| format!(":{port}"))
+            .unwrap_or_default(),
+        uri.path_and_query().map_or("", PathAndQuery::as_str)
+    ))
+}
+
+/// whether `encoding` is acceptable according to the client's `Accept-Encoding`
+fn accepts_encoding(accept_encoding: Option<&HeaderValue>, encoding: &str) -> bool {
+    let encoding = encoding.trim();
+    if encoding.eq_ignore_ascii_case("identity") {
+        return true;
+    }
+    let accept_encoding = match accept_encoding.and_then(|v| v.to_str().ok()) {
+        Some(accept_encoding) => accept_encoding,
+        None => return false,
+    };
+    accept_encoding.split(',').any(|item| {
+        let mut params = item.split(';').map(str::trim);
+        let coding = params.next().unwrap_or_default();
+        let rejected = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        !rejected && (coding == "*" || coding.eq_ignore_ascii_case(encoding))
+    })
 }
 
 #[inline]
+#[allow(clippy::too_many_arguments)]
 async fn forward(
     client: &AwcClient,
+    options: &ForwardOptions,
+    request_id: &str,
+    host: &str,
     sni: &str,
+    upstream: &Upstream,
     RequestHead {
         method,
         uri,
         version,
         headers,
+        peer_addr,
         ..
-    }: RequestHead,
-    payload: Payload,
-) -> AnyResult<HttpResponse> {
+    }: &RequestHead,
+    payload: impl Stream<Item = Result<Bytes, PayloadError>> + 'static,
+) -> SnimapResult<HttpResponse> {
+    let max_body_size = options.max_body_size.unwrap_or(usize::MAX);
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > max_body_size) {
+        return Ok(HttpResponse::PayloadTooLarge().finish());
+    }
+    // chunked bodies are only known to be too large once streamed
+    let body_too_large = Rc::new(Cell::new(false));
+    let payload = {
+        let body_too_large = body_too_large.clone();
+        let mut received = 0;
+        payload.map(move |chunk| {
+            let chunk = chunk?;
+            received += chunk.len();
+            if received > max_body_size {
+                body_too_large.set(true);
+                return Err(PayloadError::Overflow);
+            }
+            Ok(chunk)
+        })
+    };
     let mut awc_request = client
         .request(
             method.clone(),
             Uri::try_from(format!(
-                "{}://{}{}",
+                "{}://{}{}{}",
                 uri.scheme_str().unwrap_or("https"),
                 sni,
+                upstream
+                    .port
+                    .map(|port| format!(":{port}"))
+                    .unwrap_or_default(),
                 uri.path_and_query()
                     .unwrap_or(&PathAndQuery::from_static("/"))
             ))?,
         )
         .no_decompress();
-    let host = headers.get(header::HOST).unwrap().clone();
-    for (nhk, nhv) in headers.into_iter() {
-        match awc_request.headers_mut().get_mut(&nhk) {
+    if let Some(secs) = upstream.timeout_secs {
+        awc_request = awc_request.timeout(Duration::from_secs(secs));
+    }
+    let host = HeaderValue::from_str(host)?;
+    let accept_encoding = headers.get(header::ACCEPT_ENCODING);
+    for (nhk, nhv) in headers.iter().filter(|(nhk, _)| *nhk != header::HOST) {
+        // comma separated lists, `append_forwarded_for` joins the lines
+        if *nhk == X_FORWARDED_FOR || *nhk == header::FORWARDED {
+            awc_request.headers_mut().append(nhk.clone(), nhv.clone());
+            continue;
+        }
+        match awc_request.headers_mut().get_mut(nhk) {
             Some(hv) => *hv = format!("{};{}", hv.to_str()?, nhv.to_str()?).try_into()?,
             None => {
-                awc_request.headers_mut().insert(nhk, nhv);
+                awc_request.headers_mut().insert(nhk.clone(), nhv.clone());
+            }
+        }
+    }
+    awc_request.headers_mut().insert(header::HOST, host.clone());
+    if let Some(user_agent) = &upstream.user_agent {
+        let client = headers
+            .get(header::USER_AGENT)
+            .map(|v| v.to_str())
+            .transpose()?;
+        awc_request
+            .headers_mut()
+            .insert(header::USER_AGENT, user_agent.apply(client).try_into()?);
+    }
+    if let Some(rules) = &upstream.headers {
+        apply_header_rules(awc_request.headers_mut(), rules)?;
+    }
+    if let (true, Some(addr)) = (options.forwarded_for, peer_addr) {
+        append_forwarded_for(awc_request.headers_mut(), addr.ip())?;
+    }
+    let awc_response = match awc_request.send_stream(payload).await {
+        Err(_) if body_too_large.get() => return Ok(HttpResponse::PayloadTooLarge().finish()),
+        result => result,
+    }
+    .inspect(|r| {
+        log::info!(
+            target: FORWARD,
+            "{} {} \"{} {} {:?}\" host: {:?} {} {:?}",
+            request_id,
+            sni,
+            method,
+            uri.path(),
+            version,
+            host,
+            r.status(),
+            r.version(),
+        )
+    })
+    .inspect_err(|e| {
+        log::error!(
+            target: FORWARD,
+            "{} {} \"{} {} {:?}\" host: {:?} error: {}",
+            request_id,
+            sni,
+            method,
+            uri.path(),
+            version,
+            host,
+            e
+        )
+    })?;
+    let decompress = options.decompress
+        && awc_response
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|encoding| !accepts_encoding(accept_encoding, encoding));
+    let response_rules = upstream.response.unwrap_or_default();
+    let mut response = HttpResponse::build(awc_response.status());
+    for (header_name, header_value) in awc_response.headers().iter() {
+        if decompress
+            && (header_name == header::CONTENT_ENCODING || header_name == header::CONTENT_LENGTH)
+        {
+            continue;
+        }
+        if response_rules.strip_hsts() && header_name == header::STRICT_TRANSPORT_SECURITY {
+            continue;
+        }
+        if response_rules.rewrite_location() && header_name == header::LOCATION {
+            if let Some(location) = header_value
+                .to_str()
+                .ok()
+                .and_then(|location| rewrite_location(location, sni, host.to_str().ok()?))
+            {
+                response.append_header((header::LOCATION, location));
+                continue;
             }
         }
+        response.append_header((header_name.clone(), header_value.clone()));
+    }
+    if decompress {
+        let upstream_headers = awc_response.headers().clone();
+        Ok(response.streaming(Decompress::from_headers(awc_response, &upstream_headers)))
+    } else {
+        Ok(response.streaming(awc_response))
+    }
+}
+
+/// `host[:port]` or `[ipv6][:port]` without the port
+fn strip_port(authority: &str) -> &str {
+    if let Some(rest) = authority.strip_prefix('[') {
+        return rest.split_once(']').map_or(authority, |(ip, _)| ip);
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            host
+        }
+        _ => authority,
+    }
+}
+
+/// normalized hostname the client asked for, used as `SniMap` key
+fn request_host(request: &HttpRequest) -> Option<String> {
+    match request.version() {
+        Version::HTTP_09 | Version::HTTP_10 | Version::HTTP_11 => request
+            .headers()
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok()),
+        _ => request.uri().host(),
+    }
+    .map(strip_port)
+    .map(|host| normalize_hostname(host).unwrap_or_else(|| host.to_ascii_lowercase()))
+}
+
+/// shared by all workers, hostnames are fixed at startup
+#[derive(Default, Debug)]
+pub struct ForwardStats {
+    pub forwarded: AtomicU64,
+    pub per_host: HashMap<String, AtomicU64>,
+}
+
+impl ForwardStats {
+    pub fn record(&self, host: &str) {
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+        if let Some(count) = self.per_host.get(host) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// hosts with at least one request, busiest first
+    pub fn per_host_sorted(&self) -> Vec<(&str, u64)> {
+        let mut counts = self
+            .per_host
+            .iter()
+            .map(|(host, count)| (host.as_str(), count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+}
+
+impl From<&SniMap> for ForwardStats {
+    fn from(snimap: &SniMap) -> Self {
+        Self {
+            forwarded: AtomicU64::new(0),
+            per_host: snimap
+                .hostnames()
+                .into_iter()
+                .map(|host| (host.to_string(), AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+}
+
+/// a summary of every request `reverse_proxy` answered, for `--once`
+pub struct Completed(UnboundedSender<String>);
+
+impl Completed {
+    pub fn channel() -> (Self, UnboundedReceiver<String>) {
+        let (sender, receiver) = mpsc::unbounded();
+        (Self(sender), receiver)
+    }
+}
+
+/// last working candidate of each `Sni::OverrideList`, shared by all workers
+#[derive(Default, Debug)]
+pub struct PreferredSni(HashMap<String, AtomicUsize>);
+
+impl PreferredSni {
+    /// indices of `len` candidates, starting from the preferred one
+    pub fn candidates(&self, host: &str, len: usize) -> impl Iterator<Item = usize> {
+        let start = self.0.get(host).map_or(0, |i| i.load(Ordering::Relaxed));
+        (0..len).map(move |i| (start + i) % len)
+    }
+
+    pub fn prefer(&self, host: &str, index: usize) {
+        if let Some(i) = self.0.get(host) {
+            i.store(index, Ordering::Relaxed);
+        }
+    }
+}
+
+impl From<&SniMap> for PreferredSni {
+    fn from(snimap: &SniMap) -> Self {
+        Self(
+            snimap
+                .hostnames()
+                .into_iter()
+                .filter(|host| matches!(snimap.get(host), Some(Sni::OverrideList(_))))
+                .map(|host| (host.to_string(), AtomicUsize::new(0)))
+                .collect(),
+        )
+    }
+}
+
+/// bodyless requests safe to send again to another candidate
+fn is_replayable(head: &RequestHead) -> bool {
+    matches!(head.method, Method::GET | Method::HEAD | Method::OPTIONS)
+        && !head.headers().contains_key(header::TRANSFER_ENCODING)
+        && head
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .is_none_or(|len| len == "0")
+}
+
+/// the host and head of the request a redirect to a host in the map leads to,
+/// only for requests that can be replayed without their body
+fn redirect_target(
+    snimap: &SniMap,
+    host: &str,
+    head: &RequestHead,
+    response: &HttpResponse,
+) -> Option<(String, RequestHead)> {
+    if !response.status().is_redirection() || !is_replayable(head) {
+        return None;
+    }
+    let location = response.headers().get(header::LOCATION)?.to_str().ok()?;
+    let location = location.parse::<Uri>().ok()?;
+    let target = match location.authority() {
+        Some(authority)
+            if authority.port_u16().is_none_or(|port| port == 443)
+                && location.scheme_str() == Some("https") =>
+        {
+            normalize_hostname(authority.host())?
+        }
+        Some(_) => return None,
+        None if location.path().starts_with('/') => host.to_string(),
+        None => return None,
+    };
+    snimap.get(&target)?;
+    let mut head = head.clone();
+    head.uri = location.path_and_query()?.as_str().parse().ok()?;
+    if target != host {
+        // credentials of `host` are not meant for another host
+        head.headers.remove(header::COOKIE);
+        head.headers.remove(header::AUTHORIZATION);
+    }
+    head.headers
+        .insert(header::HOST, HeaderValue::from_str(&target).ok()?);
+    Some((target, head))
+}
+
+fn is_connect_error(error: &SnimapError) -> bool {
+    matches!(
+        error,
+        SnimapError::Forward(e)
+            if matches!(e.downcast_ref::<SendRequestError>(), Some(SendRequestError::Connect(_)))
+    )
+}
+
+/// the handshake failed or the upstream hung up during it, as opposed to tcp errors
+fn is_tls_error(error: &SnimapError) -> bool {
+    let SnimapError::Forward(e) = error else {
+        return false;
+    };
+    match e.downcast_ref::<SendRequestError>() {
+        Some(SendRequestError::Connect(awc::error::ConnectError::Io(e))) => {
+            e.kind() == io::ErrorKind::UnexpectedEof
+                || e.get_ref().is_some_and(|e| e.is::<rustls::Error>())
+        }
+        _ => false,
+    }
+}
+
+/// the upstream presented a certificate that does not verify, unlike alerts or
+/// hang-ups which are how a cdn refuses an sni it does not like
+fn is_certificate_error(error: &SnimapError) -> bool {
+    let SnimapError::Forward(e) = error else {
+        return false;
+    };
+    let Some(SendRequestError::Connect(awc::error::ConnectError::Io(e))) =
+        e.downcast_ref::<SendRequestError>()
+    else {
+        return false;
+    };
+    matches!(
+        e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()),
+        Some(
+            rustls::Error::InvalidCertificateEncoding
+                | rustls::Error::InvalidCertificateSignatureType
+                | rustls::Error::InvalidCertificateSignature
+                | rustls::Error::InvalidCertificateData(_)
+        )
+    )
+}
+
+/// whether a failed request with `sni` should drop the cached address, without sni
+/// a cdn answering with its default certificate is expected unless retried with one
+fn invalidates_on_error(sni: &Sni, options: &ForwardOptions) -> bool {
+    match sni {
+        // candidates of a list are handled one by one in `forward_override_list`
+        Sni::OverrideList(_) => false,
+        Sni::Disable => options.sni_fallback,
+        Sni::Override(_) | Sni::Remain(_) => true,
+    }
+}
+
+/// a cached address presenting the wrong certificate may have been handed to
+/// another tenant, drop it so the next connection looks `connect_host` up again
+fn invalidate_on_certificate_error(
+    resolver: &dyn UpstreamResolver,
+    connect_host: &str,
+    error: &SnimapError,
+) {
+    if is_certificate_error(error) && resolver.invalidate(connect_host) {
+        log::warn!(
+            target: FORWARD,
+            "{connect_host} presented an invalid certificate, invalidate its address"
+        );
+    }
+}
+
+/// forward without sni, then once more with `host` as sni if that handshake failed
+#[allow(clippy::too_many_arguments)]
+async fn forward_disable_sni(
+    client_pair: &ClientPair,
+    options: &ForwardOptions,
+    request_id: &str,
+    host: &str,
+    upstream: &Upstream,
+    head: &RequestHead,
+    payload: impl Stream<Item = Result<Bytes, PayloadError>> + 'static,
+) -> SnimapResult<HttpResponse> {
+    let client = client_pair.client_disable_sni();
+    if !options.sni_fallback || !is_replayable(head) {
+        return forward(
+            client, options, request_id, host, host, upstream, head, payload,
+        )
+        .await;
+    }
+    match forward(
+        client, options, request_id, host, host, upstream, head, payload,
+    )
+    .await
+    {
+        Err(e) if is_tls_error(&e) => {
+            log::warn!(
+                target: FORWARD,
+                "{request_id} {host} handshake without sni failed, retry with sni"
+            );
+            forward(
+                client_pair.client_enable_sni(),
+                options,
+                request_id,
+                host,
+                host,
+                upstream,
+                head,
+                stream::empty(),
+            )
+            .await
+        }
+        result => result,
     }
-    let awc_response = awc_request
-        .send_stream(payload)
+}
+
+/// try `snis` from the preferred one until an upstream connects,
+/// along with the sni the result came from
+#[allow(clippy::too_many_arguments)]
+async fn forward_override_list<'a>(
+    client_pair: &ClientPair,
+    options: &ForwardOptions,
+    request_id: &str,
+    preferred_sni: &PreferredSni,
+    host: &str,
+    snis: &'a [String],
+    upstream: &Upstream,
+    head: &RequestHead,
+    payload: impl Stream<Item = Result<Bytes, PayloadError>> + 'static,
+) -> (&'a str, SnimapResult<HttpResponse>) {
+    let replayable = is_replayable(head);
+    let mut payload = Some(payload);
+    let mut last_error = None;
+    for index in preferred_sni.candidates(host, snis.len()) {
+        let body = match payload.take() {
+            Some(payload) => payload.left_stream(),
+            None => stream::empty().right_stream(),
+        };
+        match forward(
+            client_pair.client_override_sni(),
+            options,
+            request_id,
+            host,
+            &snis[index],
+            upstream,
+            head,
+            body,
+        )
         .await
-        .inspect(|r| {
-            log::info!(
-                target: "forward",
-                "{} \"{} {} {:?}\" host: {:?} {} {:?}",
-                sni,
-                method,
-                uri.path(),
-                version,
+        {
+            Err(e) if is_connect_error(&e) => {
+                invalidate_on_certificate_error(client_pair.resolver(), &snis[index], &e);
+                preferred_sni.prefer(host, (index + 1) % snis.len());
+                if !replayable {
+                    return (&snis[index], Err(e));
+                }
+                log::warn!(
+                    target: FORWARD,
+                    "{request_id} {host} sni={} failed, try next",
+                    snis[index]
+                );
+                last_error = Some((&snis[index], e));
+            }
+            result => {
+                if result.is_ok() {
+                    preferred_sni.prefer(host, index);
+                }
+                return (&snis[index], result);
+            }
+        }
+    }
+    let (sni, e) = last_error.expect("`Sni::OverrideList` has at least two candidates");
+    (sni, Err(e))
+}
+
+/// one line for the `sni` log target, `-` as sni when none is sent
+fn sni_line(
+    request_id: &str,
+    host: &str,
+    sni: &Sni,
+    connect_host: &str,
+    resolved: Option<SocketAddr>,
+) -> String {
+    let sent_sni = match sni {
+        Sni::Disable => "-",
+        _ => connect_host,
+    };
+    let resolved = resolved.map_or_else(|| "unresolved".to_string(), |a| a.ip().to_string());
+    format!(
+        "{request_id} host={host} mode={} sni={sent_sni} ip={resolved}",
+        sni.mode_str()
+    )
+}
+
+/// pick the client and sni for the `Sni` mode of `host`
+#[allow(clippy::too_many_arguments)]
+async fn forward_by_sni(
+    client_pair: &ClientPair,
+    options: &ForwardOptions,
+    request_id: &str,
+    preferred_sni: &PreferredSni,
+    host: &str,
+    sni: &Sni,
+    upstream: &Upstream,
+    head: &RequestHead,
+    payload: impl Stream<Item = Result<Bytes, PayloadError>> + 'static,
+) -> SnimapResult<HttpResponse> {
+    // the hostname the upstream connection was made to, which is what got resolved
+    let (connect_host, result) = match sni {
+        Sni::Disable => (
+            host,
+            forward_disable_sni(
+                client_pair,
+                options,
+                request_id,
                 host,
-                r.status(),
-                r.version(),
+                upstream,
+                head,
+                payload,
             )
-        })
-        .inspect_err(|e| {
-            log::error!(
-                target: "forward",
-                "{} \"{} {} {:?}\" host: {:?} error: {}",
+            .await,
+        ),
+        Sni::Override(sni) => (
+            sni.as_str(),
+            forward(
+                client_pair.client_override_sni(),
+                options,
+                request_id,
+                host,
                 sni,
-                method,
-                uri.path(),
-                version,
+                upstream,
+                head,
+                payload,
+            )
+            .await,
+        ),
+        Sni::OverrideList(snis) => {
+            forward_override_list(
+                client_pair,
+                options,
+                request_id,
+                preferred_sni,
                 host,
-                e
+                snis,
+                upstream,
+                head,
+                payload,
             )
-        })?;
-    let mut response = HttpResponse::build(awc_response.status());
-    for (header_name, header_value) in awc_response.headers().iter() {
-        response.append_header((header_name.clone(), header_value.clone()));
+            .await
+        }
+        Sni::Remain(sni) => (
+            sni.as_str(),
+            forward(
+                client_pair.client_enable_sni(),
+                options,
+                request_id,
+                host,
+                sni,
+                upstream,
+                head,
+                payload,
+            )
+            .await,
+        ),
+    };
+    if let (Err(e), true) = (&result, invalidates_on_error(sni, options)) {
+        invalidate_on_certificate_error(client_pair.resolver(), connect_host, e);
+    }
+    if log::log_enabled!(target: SNI, log::Level::Debug) {
+        let resolved = client_pair.resolver().cached(connect_host);
+        log::debug!(target: SNI, "{}", sni_line(request_id, host, sni, connect_host, resolved));
+    }
+    result
+}
+
+/// `GET /` on `host` through the same path a proxied request takes
+pub async fn check_host(
+    snimap: &SniMap,
+    client_pair: &ClientPair,
+    host: &str,
+) -> SnimapResult<StatusCode> {
+    let sni = snimap
+        .get(host)
+        .ok_or_else(|| SnimapError::Forward(format!("{host} is not in the map").into()))?;
+    let mut head = RequestHead::default();
+    head.headers
+        .insert(header::HOST, HeaderValue::from_str(host)?);
+    forward_by_sni(
+        client_pair,
+        &ForwardOptions::default(),
+        "check",
+        &PreferredSni::default(),
+        host,
+        sni,
+        &snimap.upstream(host),
+        &head,
+        stream::empty(),
+    )
+    .await
+    .map(|response| response.status())
+}
+
+/// what a reverse proxy has no business forwarding
+fn is_method_allowed(method: &Method) -> bool {
+    !matches!(*method, Method::CONNECT | Method::TRACE)
+}
+
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, DELETE, OPTIONS, PATCH";
+
+pub const HEALTHZ_PATH: &str = "/healthz";
+
+/// requests addressed to the proxy itself rather than a proxied hostname,
+/// ip literals other than loopback may be hosts in the `SniMap`
+fn is_local_request(ctx: &GuardContext) -> bool {
+    let head = ctx.head();
+    head.headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| head.uri.host())
+        .map(strip_port)
+        .is_none_or(|host| {
+            host.eq_ignore_ascii_case("localhost")
+                || host
+                    .parse::<IpAddr>()
+                    .is_ok_and(|ip| ip.is_loopback() || ip.is_unspecified())
+        })
+}
+
+/// liveness probe, proxied sites keep their own `/healthz`
+pub fn healthz_route() -> Route {
+    web::get()
+        .guard(guard::fn_guard(is_local_request))
+        .to(|| async { HttpResponse::Ok().body("ok") })
+}
+
+pub const RESOLVER_CACHE_PATH: &str = "/admin/resolver";
+
+/// the resolver cache as json, for clients on this machine only
+pub fn resolver_cache_route() -> Route {
+    web::get()
+        .guard(guard::fn_guard(is_local_request))
+        .to(resolver_cache)
+}
+
+fn is_loopback_peer(request: &HttpRequest) -> bool {
+    request
+        .peer_addr()
+        .is_some_and(|addr| addr.ip().is_loopback())
+}
+
+async fn resolver_cache(request: HttpRequest, resolver: Data<SniMapResolver>) -> HttpResponse {
+    if !is_loopback_peer(&request) {
+        return HttpResponse::Forbidden().body("admin endpoints are loopback only");
+    }
+    let snapshot = resolver
+        .snapshot()
+        .into_iter()
+        .map(|(host, socket_addr)| {
+            let resolved = socket_addr.map_or_else(|| "unresolved".to_string(), |a| a.to_string());
+            (host, resolved)
+        })
+        .collect::<BTreeMap<_, _>>();
+    HttpResponse::Ok().json(snapshot)
+}
+
+pub const RERESOLVE_PATH: &str = "/admin/resolve";
+
+/// `POST /admin/resolve?host=x` drops the cached address of `x` and looks it up again
+pub fn reresolve_route() -> Route {
+    web::post()
+        .guard(guard::fn_guard(is_local_request))
+        .to(reresolve)
+}
+
+async fn reresolve(
+    request: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    resolver: Data<SniMapResolver>,
+) -> SnimapResult<HttpResponse> {
+    if !is_loopback_peer(&request) {
+        return Ok(HttpResponse::Forbidden().body("admin endpoints are loopback only"));
+    }
+    let host = match query.get("host") {
+        Some(host) => normalize_hostname(host).unwrap_or_else(|| host.to_ascii_lowercase()),
+        None => return Ok(HttpResponse::BadRequest().body("missing `host` query parameter")),
+    };
+    if !resolver.contains(&host) {
+        return Ok(HttpResponse::NotFound().body(format!("{host} is not in the resolver cache")));
     }
-    Ok(response.streaming(awc_response))
+    let socket_addr = web::block({
+        let host = host.clone();
+        move || resolver.reresolve(&host)
+    })
+    .await
+    .map_err(|e| SnimapError::Resolve(e.into()))?;
+    Ok(match socket_addr {
+        Some(socket_addr) => {
+            HttpResponse::Ok().json(BTreeMap::from([(host, socket_addr.to_string())]))
+        }
+        None => HttpResponse::BadGateway().body(format!("failed to resolve {host}")),
+    })
 }
 
 pub async fn reverse_proxy(
@@ -134,27 +1058,132 @@ pub async fn reverse_proxy(
     payload: Payload,
     snimap: Data<SniMap>,
     client_pair: Data<ClientPair>,
-) -> AnyResult<HttpResponse> {
-    match match request.version() {
-        Version::HTTP_09 | Version::HTTP_10 | Version::HTTP_11 => request
-            .headers()
-            .get(header::HOST)
-            .map(|h| h.to_str().unwrap()),
-        _ => request.uri().host(),
-    } {
-        Some(host) => match snimap.get(host) {
+    options: Data<ForwardOptions>,
+    stats: Data<ForwardStats>,
+    preferred_sni: Data<PreferredSni>,
+) -> SnimapResult<HttpResponse> {
+    let request_id = request_id(request.headers());
+    let mut response = proxy(
+        &request,
+        payload,
+        &snimap,
+        &client_pair,
+        &options,
+        &stats,
+        &preferred_sni,
+        request_id.to_str().unwrap_or_default(),
+    )
+    .await
+    .unwrap_or_else(|e| e.error_response());
+    if let Some(completed) = request.app_data::<Data<Completed>>() {
+        // the receiver is gone once the server is stopping
+        let _ = completed.0.unbounded_send(format!(
+            "{} {} {}{} {}",
+            request_id.to_str().unwrap_or_default(),
+            request.method(),
+            request_host(&request).unwrap_or_default(),
+            request.uri(),
+            response.status()
+        ));
+    }
+    response.headers_mut().insert(X_REQUEST_ID, request_id);
+    Ok(response)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn proxy(
+    request: &HttpRequest,
+    payload: Payload,
+    snimap: &SniMap,
+    client_pair: &ClientPair,
+    options: &ForwardOptions,
+    stats: &ForwardStats,
+    preferred_sni: &PreferredSni,
+    request_id: &str,
+) -> SnimapResult<HttpResponse> {
+    let peer_ip = request.peer_addr().map(|addr| addr.ip());
+    if !options.is_allowed(peer_ip) {
+        log::warn!(target: PROXY, "reject client {:?}", peer_ip);
+        return Ok(HttpResponse::Forbidden().body("client address is not allowed"));
+    }
+    if !is_method_allowed(request.method()) {
+        return Ok(HttpResponse::MethodNotAllowed()
+            .insert_header((header::ALLOW, ALLOWED_METHODS))
+            .finish());
+    }
+    match request_host(request) {
+        Some(host) => match snimap.get(&host) {
             Some(sni) => {
-                let mut head = request.head().clone();
-                head.headers_mut()
-                    .insert(header::HOST, header::HeaderValue::from_str(host)?);
-                match sni {
-                    Sni::Disable => {
-                        forward(client_pair.client_disable_sni(), host, head, payload).await
-                    }
-                    Sni::Override(sni) | Sni::Remain(sni) => {
-                        forward(client_pair.client_enable_sni(), sni, head, payload).await
-                    }
+                log::debug!(target: FORWARD, "{host} {sni}");
+                let upstream = snimap.upstream(&host);
+                stats.record(&host);
+                let mut response = forward_by_sni(
+                    client_pair,
+                    options,
+                    request_id,
+                    preferred_sni,
+                    &host,
+                    sni,
+                    &upstream,
+                    request.head(),
+                    payload,
+                )
+                .await?;
+                let mut redirected: Option<(String, RequestHead)> = None;
+                for _ in 0..options.follow_redirects {
+                    let (host, head) = redirected
+                        .as_ref()
+                        .map_or((host.as_str(), request.head()), |(host, head)| (host, head));
+                    let Some((host, head)) = redirect_target(snimap, host, head, &response) else {
+                        break;
+                    };
+                    // read to the end so its connection can serve the next hop
+                    let (redirect, body) = response.into_parts();
+                    let redirect = redirect.set_body(
+                        to_bytes(body)
+                            .await
+                            .map_err(|e| SnimapError::Forward(e.to_string().into()))?,
+                    );
+                    log::debug!(target: FORWARD, "{request_id} follow redirect to {host}{}", head.uri);
+                    stats.record(&host);
+                    response = match forward_by_sni(
+                        client_pair,
+                        options,
+                        request_id,
+                        preferred_sni,
+                        &host,
+                        snimap
+                            .get(&host)
+                            .expect("`redirect_target` only returns hosts in the map"),
+                        &snimap.upstream(&host),
+                        &head,
+                        stream::empty(),
+                    )
+                    .await
+                    {
+                        Ok(response) => response,
+                        // the redirect is still an answer the client can act on
+                        Err(_) => return Ok(redirect.map_into_boxed_body()),
+                    };
+                    redirected = Some((host, head));
                 }
+                Ok(response)
+            }
+            None if options.passthrough_unknown => {
+                log::debug!(target: FORWARD, "{host} not in map, pass through");
+                stats.record(&host);
+                forward_by_sni(
+                    client_pair,
+                    options,
+                    request_id,
+                    preferred_sni,
+                    &host,
+                    &Sni::Remain(host.clone()),
+                    &Upstream::default(),
+                    request.head(),
+                    payload,
+                )
+                .await
             }
             None => Ok(HttpResponse::Forbidden().body(format!(
                 "`hostname = \"{host}\"` is not enabled in config.toml"
@@ -169,39 +1198,63 @@ mod tests {
     use std::sync::Arc;
 
     use actix_web::{
+        dev::{ServiceFactory, ServiceRequest, ServiceResponse},
         http, test,
         web::{to, Data},
         App,
     };
 
     use crate::{
-        config::{Mapping, SniMap, Switchable},
-        handler::{reverse_proxy, ClientPair},
+        config::{Mapping, SniMap},
+        handler::{
+            check_host, healthz_route, reresolve_route, resolver_cache_route, reverse_proxy,
+            ClientOptions, ClientPair, ForwardOptions, ForwardStats, PreferredSni, HEALTHZ_PATH,
+            RERESOLVE_PATH, RESOLVER_CACHE_PATH,
+        },
         resolver::SniMapResolver,
         tlscert::{rustls_client_config, DisableSni},
     };
 
+    /// trusts the system roots and resolves through `snimap`
+    fn system_client_pair(snimap: &SniMap) -> ClientPair {
+        ClientPair::new(
+            Arc::new(rustls_client_config()),
+            Arc::new(rustls_client_config().disable_sni()),
+            SniMapResolver::from_snimap(snimap),
+            ClientOptions::default(),
+        )
+    }
+
+    /// the app `main` serves, minus the admin routes
+    fn proxy_app(
+        snimap: SniMap,
+        client_pair: ClientPair,
+        options: ForwardOptions,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        App::new()
+            .app_data(Data::new(ForwardStats::from(&snimap)))
+            .app_data(Data::new(PreferredSni::from(&snimap)))
+            .app_data(Data::new(snimap))
+            .app_data(Data::new(client_pair))
+            .app_data(Data::new(options))
+            .default_service(to(reverse_proxy))
+    }
+
     async fn test_reverse_proxy_use(
         snimap: SniMap,
         headers: Option<Vec<(&str, &str)>>,
     ) -> http::StatusCode {
-        let snimap_resolver = SniMapResolver::from_snimap(&snimap);
-        let snimap_data = Data::new(snimap);
-        let (client_config_enable_sni, client_config_disable_sni) = (
-            Arc::new(rustls_client_config()),
-            Arc::new(rustls_client_config().disable_sni()),
-        );
-        let mut srv = test::init_service(
-            App::new()
-                .app_data(snimap_data.clone())
-                .app_data(Data::new(ClientPair::new(
-                    client_config_enable_sni.clone(),
-                    client_config_disable_sni.clone(),
-                    snimap_resolver,
-                )))
-                .default_service(to(reverse_proxy)),
-        )
-        .await;
+        let client_pair = system_client_pair(&snimap);
+        let srv =
+            test::init_service(proxy_app(snimap, client_pair, ForwardOptions::default())).await;
 
         let mut test_req = test::TestRequest::get().uri("/");
 
@@ -211,13 +1264,145 @@ mod tests {
             }
         }
 
-        let resp = test::call_service(&mut srv, test_req.to_request()).await;
+        let resp = test::call_service(&srv, test_req.to_request()).await;
 
         dbg!(resp.response().body());
 
         resp.status()
     }
 
+    #[actix_web::test]
+    async fn test_tcp_tuned_connector() {
+        use std::{net::TcpListener, time::Duration};
+
+        use actix_tls::connect::{ConnectInfo, Connector, Resolver};
+        use actix_web::dev::Service;
+        use socket2::SockRef;
+
+        use super::TcpTuned;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}/", listener.local_addr().unwrap())
+            .parse::<http::Uri>()
+            .unwrap();
+
+        let connector = TcpTuned {
+            connector: Connector::new(Resolver::default()).service(),
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(60)),
+        };
+        let connection = connector.call(ConnectInfo::new(uri.clone())).await.unwrap();
+        assert!(connection.io_ref().nodelay().unwrap());
+        assert!(SockRef::from(connection.io_ref()).keepalive().unwrap());
+
+        let connector = TcpTuned {
+            connector: Connector::new(Resolver::default()).service(),
+            nodelay: false,
+            keepalive: None,
+        };
+        let connection = connector.call(ConnectInfo::new(uri)).await.unwrap();
+        assert!(!connection.io_ref().nodelay().unwrap());
+
+        // the tuned connector still plugs into awc next to the custom resolver
+        let snimap = SniMap::new();
+        ClientPair::new(
+            Arc::new(rustls_client_config()),
+            Arc::new(rustls_client_config().disable_sni()),
+            SniMapResolver::from_snimap(&snimap),
+            ClientOptions {
+                tcp_nodelay: false,
+                tcp_keepalive: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_passthrough_unknown() {
+        async fn status(passthrough_unknown: bool) -> http::StatusCode {
+            let client_pair = ClientPair::new(
+                Arc::new(rustls_client_config()),
+                Arc::new(rustls_client_config().disable_sni()),
+                SniMapResolver::from_snimap(&SniMap::new())
+                    .with_passthrough_unknown(passthrough_unknown),
+                ClientOptions::default(),
+            );
+            let options = ForwardOptions {
+                passthrough_unknown,
+                ..Default::default()
+            };
+            let srv = test::init_service(proxy_app(SniMap::new(), client_pair, options)).await;
+            let req = test::TestRequest::get()
+                .uri("/")
+                .insert_header(("host", "localhost"))
+                .to_request();
+            test::call_service(&srv, req).await.status()
+        }
+
+        assert_eq!(status(false).await, http::StatusCode::FORBIDDEN);
+        // forwarded to localhost:443, where nothing is expected to listen
+        assert_eq!(status(true).await, http::StatusCode::BAD_GATEWAY);
+    }
+
+    #[actix_web::test]
+    async fn test_method_not_allowed() {
+        use actix_web::http::{header, Method};
+
+        let snimap = SniMap::builder().remain("example.com").build();
+        let client_pair = system_client_pair(&snimap);
+        let srv =
+            test::init_service(proxy_app(snimap, client_pair, ForwardOptions::default())).await;
+        for method in [Method::CONNECT, Method::TRACE] {
+            let req = test::TestRequest::default()
+                .method(method)
+                .uri("/")
+                .insert_header(("host", "example.com"))
+                .to_request();
+            let resp = test::call_service(&srv, req).await;
+            assert_eq!(resp.status(), http::StatusCode::METHOD_NOT_ALLOWED);
+            assert!(resp.headers().contains_key(header::ALLOW));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_request_id() {
+        let srv = test::init_service(proxy_app(
+            SniMap::new(),
+            system_client_pair(&SniMap::new()),
+            ForwardOptions::default(),
+        ))
+        .await;
+
+        let req = test::TestRequest::get()
+            .insert_header(("host", "example.com"))
+            .insert_header(("x-request-id", "abc-123"))
+            .to_request();
+        let resp = test::call_service(&srv, req).await;
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers().get("x-request-id").unwrap(), "abc-123");
+
+        let ids = futures::future::join_all((0..2).map(|_| {
+            let req = test::TestRequest::get()
+                .insert_header(("host", "example.com"))
+                .to_request();
+            test::call_service(&srv, req)
+        }))
+        .await
+        .into_iter()
+        .map(|resp| resp.headers().get("x-request-id").unwrap().clone())
+        .collect::<Vec<_>>();
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[actix_web::test]
+    async fn test_check_host_not_in_map() {
+        let snimap = SniMap::new();
+        let client_pair = system_client_pair(&snimap);
+        assert!(check_host(&snimap, &client_pair, "example.com")
+            .await
+            .is_err());
+    }
+
     #[actix_web::test]
     async fn test_reverse_proxy_no_host() {
         assert_eq!(
@@ -251,7 +1436,7 @@ mod tests {
     async fn test_reverse_proxy_disable_sni() {
         assert!(
             test_reverse_proxy_use(
-                Mapping::new("en.wikipedia.org").disable_sni().into(),
+                SniMap::builder().disable("en.wikipedia.org").build(),
                 Some(vec![("host", "en.wikipedia.org")])
             )
             .await
@@ -264,9 +1449,9 @@ mod tests {
     async fn test_reverse_proxy_enable_sni_domain_fronting() {
         assert!(
             test_reverse_proxy_use(
-                Mapping::new("www.pixiv.net")
-                    .override_sni("www.fanbox.cc")
-                    .into(),
+                SniMap::builder()
+                    .override_sni("www.pixiv.net", "www.fanbox.cc")
+                    .build(),
                 Some(vec![("host", "www.pixiv.net")])
             )
             .await
@@ -279,31 +1464,17 @@ mod tests {
     async fn test_reverse_proxy_post() {
         use actix_web::body::to_bytes;
 
-        let snimap = Mapping::new("httpbin.org").into();
-        let snimap_resolver = SniMapResolver::from_snimap(&snimap);
-        let snimap_data = Data::new(snimap);
-        let (client_config_enable_sni, client_config_disable_sni) = (
-            Arc::new(rustls_client_config()),
-            Arc::new(rustls_client_config().disable_sni()),
-        );
-        let mut srv = test::init_service(
-            App::new()
-                .app_data(snimap_data.clone())
-                .app_data(Data::new(ClientPair::new(
-                    client_config_enable_sni.clone(),
-                    client_config_disable_sni.clone(),
-                    snimap_resolver,
-                )))
-                .default_service(to(reverse_proxy)),
-        )
-        .await;
+        let snimap: SniMap = Mapping::new("httpbin.org").into();
+        let client_pair = system_client_pair(&snimap);
+        let srv =
+            test::init_service(proxy_app(snimap, client_pair, ForwardOptions::default())).await;
 
         let test_req = test::TestRequest::post()
             .uri("/post")
             .insert_header(("host", "httpbin.org"))
             .set_json(r#"{"data":"test_reverse_proxy_post"}"#);
 
-        let resp = test::call_service(&mut srv, test_req.to_request()).await;
+        let resp = test::call_service(&srv, test_req.to_request()).await;
 
         let body = String::from_utf8(
             to_bytes(resp.into_body())
@@ -320,31 +1491,17 @@ mod tests {
     async fn test_reverse_proxy_cookie() {
         use actix_web::body::to_bytes;
 
-        let snimap = Mapping::new("httpbin.org").into();
-        let snimap_resolver = SniMapResolver::from_snimap(&snimap);
-        let snimap_data = Data::new(snimap);
-        let (client_config_enable_sni, client_config_disable_sni) = (
-            Arc::new(rustls_client_config()),
-            Arc::new(rustls_client_config().disable_sni()),
-        );
-        let mut srv = test::init_service(
-            App::new()
-                .app_data(snimap_data.clone())
-                .app_data(Data::new(ClientPair::new(
-                    client_config_enable_sni.clone(),
-                    client_config_disable_sni.clone(),
-                    snimap_resolver,
-                )))
-                .default_service(to(reverse_proxy)),
-        )
-        .await;
+        let snimap: SniMap = Mapping::new("httpbin.org").into();
+        let client_pair = system_client_pair(&snimap);
+        let srv =
+            test::init_service(proxy_app(snimap, client_pair, ForwardOptions::default())).await;
 
         let test_req = test::TestRequest::get()
             .uri("/cookies")
             .insert_header(("host", "httpbin.org"))
             .insert_header(("cookie", "a=b; c=d; e=fffff"));
 
-        let resp = test::call_service(&mut srv, test_req.to_request()).await;
+        let resp = test::call_service(&srv, test_req.to_request()).await;
 
         let body = String::from_utf8(
             to_bytes(resp.into_body())
@@ -356,4 +1513,855 @@ mod tests {
 
         assert!(dbg!(body).contains("fffff"))
     }
+
+    #[actix_web::test]
+    async fn test_client_pair_h2() {
+        use crate::tlscert::EnableH2;
+
+        let snimap_resolver = SniMapResolver::from_snimap(&Mapping::new("www.google.com").into());
+        let client_pair = ClientPair::new(
+            Arc::new(rustls_client_config().enable_h2()),
+            Arc::new(rustls_client_config().disable_sni().enable_h2()),
+            snimap_resolver,
+            ClientOptions::default(),
+        );
+
+        let resp = client_pair
+            .client_enable_sni()
+            .get("https://www.google.com/")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.version(), http::Version::HTTP_2);
+
+        let resp = client_pair
+            .client_override_sni()
+            .get("https://www.google.com/")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.version(), http::Version::HTTP_11);
+    }
+
+    #[actix_web::test]
+    async fn test_request_host() {
+        use crate::handler::request_host;
+
+        for (host, expected) in [
+            ("en.wikipedia.org", "en.wikipedia.org"),
+            ("EN.Wikipedia.ORG", "en.wikipedia.org"),
+            ("EN.Wikipedia.ORG:443", "en.wikipedia.org"),
+            ("en.wikipedia.org:8443", "en.wikipedia.org"),
+            ("1.2.3.4:443", "1.2.3.4"),
+            ("[::1]:443", "::1"),
+            ("[::1]", "::1"),
+        ] {
+            let request = test::TestRequest::get()
+                .insert_header(("host", host))
+                .to_http_request();
+            assert_eq!(request_host(&request).as_deref(), Some(expected), "{host}");
+        }
+        assert_eq!(
+            request_host(&test::TestRequest::get().to_http_request()),
+            None
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_resolver_cache_route() {
+        use std::collections::HashMap;
+
+        let snimap = SniMap::builder()
+            .override_sni("example.com", "example.net")
+            .build();
+        let snimap_resolver = SniMapResolver::from_snimap(&snimap).with_overrides(&HashMap::from(
+            [("example.com".to_string(), "1.2.3.4".parse().unwrap())],
+        ));
+        let srv = test::init_service(
+            App::new()
+                .app_data(Data::new(snimap_resolver))
+                .route(RESOLVER_CACHE_PATH, resolver_cache_route()),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(RESOLVER_CACHE_PATH)
+            .insert_header(("host", "127.0.0.1"))
+            .peer_addr("127.0.0.1:50000".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&srv, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(
+            test::read_body(resp).await,
+            r#"{"example.com":"1.2.3.4:443","example.net":"unresolved"}"#
+        );
+
+        let req = test::TestRequest::get()
+            .uri(RESOLVER_CACHE_PATH)
+            .insert_header(("host", "127.0.0.1"))
+            .peer_addr("10.0.0.1:50000".parse().unwrap())
+            .to_request();
+        assert_eq!(
+            test::call_service(&srv, req).await.status(),
+            http::StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_reresolve_route() {
+        use std::{collections::HashMap, sync::atomic::Ordering};
+
+        let snimap = SniMap::builder()
+            .override_sni("example.com", "localhost")
+            .build();
+        let snimap_resolver = SniMapResolver::from_snimap(&snimap).with_overrides(&HashMap::from(
+            [("example.com".to_string(), "1.2.3.4".parse().unwrap())],
+        ));
+        let srv = test::init_service(
+            App::new()
+                .app_data(Data::new(snimap_resolver.clone()))
+                .route(RERESOLVE_PATH, reresolve_route()),
+        )
+        .await;
+        let post = |query: &str| {
+            test::TestRequest::post()
+                .uri(&format!("{RERESOLVE_PATH}{query}"))
+                .insert_header(("host", "localhost"))
+                .peer_addr("127.0.0.1:50000".parse().unwrap())
+                .to_request()
+        };
+
+        let resp = test::call_service(&srv, post("?host=Example.com")).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(
+            test::read_body(resp).await,
+            r#"{"example.com":"1.2.3.4:443"}"#
+        );
+
+        let resp = test::call_service(&srv, post("?host=localhost")).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(snimap_resolver.stats().lookups.load(Ordering::Relaxed), 1);
+
+        for (query, status) in [
+            ("?host=unknown.com", http::StatusCode::NOT_FOUND),
+            ("", http::StatusCode::BAD_REQUEST),
+        ] {
+            assert_eq!(test::call_service(&srv, post(query)).await.status(), status);
+        }
+
+        let req = test::TestRequest::post()
+            .uri(&format!("{RERESOLVE_PATH}?host=localhost"))
+            .insert_header(("host", "localhost"))
+            .peer_addr("10.0.0.1:50000".parse().unwrap())
+            .to_request();
+        assert_eq!(
+            test::call_service(&srv, req).await.status(),
+            http::StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_healthz() {
+        let srv = test::init_service(
+            proxy_app(
+                SniMap::new(),
+                system_client_pair(&SniMap::new()),
+                ForwardOptions::default(),
+            )
+            .route(HEALTHZ_PATH, healthz_route()),
+        )
+        .await;
+
+        for host in ["127.0.0.1:443", "localhost", "[::1]"] {
+            let req = test::TestRequest::get()
+                .uri(HEALTHZ_PATH)
+                .insert_header(("host", host))
+                .to_request();
+            let resp = test::call_service(&srv, req).await;
+            assert_eq!(resp.status(), http::StatusCode::OK, "{host}");
+            assert_eq!(test::read_body(resp).await, "ok");
+        }
+
+        for host in ["example.com", "1.2.3.4", "[2001:db8::1]:443"] {
+            let proxied = test::TestRequest::get()
+                .uri(HEALTHZ_PATH)
+                .insert_header(("host", host))
+                .to_request();
+            assert_eq!(
+                test::call_service(&srv, proxied).await.status(),
+                http::StatusCode::FORBIDDEN,
+                "{host}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_reverse_proxy_allowed_ips() {
+        let options = ForwardOptions {
+            allowed_ips: Some(vec!["192.168.1.0/24".parse().unwrap()]),
+            ..Default::default()
+        };
+        let srv = test::init_service(proxy_app(
+            SniMap::new(),
+            system_client_pair(&SniMap::new()),
+            options,
+        ))
+        .await;
+
+        let allowed = test::TestRequest::get()
+            .peer_addr("192.168.1.20:50000".parse().unwrap())
+            .to_request();
+        assert_eq!(
+            test::call_service(&srv, allowed).await.status(),
+            http::StatusCode::NOT_FOUND
+        );
+
+        let denied = test::TestRequest::get()
+            .peer_addr("10.0.0.1:50000".parse().unwrap())
+            .insert_header(("host", "example.com"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&srv, denied).await.status(),
+            http::StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_forward_stats() {
+        use std::sync::atomic::Ordering;
+
+        let snimap = SniMap::builder()
+            .remain("a.example")
+            .remain("b.example")
+            .build();
+        let stats = ForwardStats::from(&snimap);
+        stats.record("a.example");
+        stats.record("b.example");
+        stats.record("b.example");
+        stats.record("unknown.example");
+
+        assert_eq!(stats.forwarded.load(Ordering::Relaxed), 4);
+        assert_eq!(
+            stats.per_host_sorted(),
+            vec![("b.example", 2), ("a.example", 1)]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_reverse_proxy_max_body_size() {
+        let snimap = SniMap::from(Mapping::new("example.com"));
+        let client_pair = system_client_pair(&snimap);
+        let options = ForwardOptions {
+            max_body_size: Some(1024),
+            ..Default::default()
+        };
+        let srv = test::init_service(proxy_app(snimap, client_pair, options)).await;
+
+        let req = test::TestRequest::post()
+            .insert_header(("host", "example.com"))
+            .insert_header(("content-length", "2048"))
+            .set_payload(vec![b'x'; 2048])
+            .to_request();
+        assert_eq!(
+            test::call_service(&srv, req).await.status(),
+            http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_plain_http_listener() {
+        use actix_web::HttpServer;
+
+        let server = HttpServer::new(|| {
+            proxy_app(
+                SniMap::new(),
+                system_client_pair(&SniMap::new()),
+                ForwardOptions::default(),
+            )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let response = awc::Client::default()
+            .get(format!("http://{addr}/"))
+            .insert_header(("host", "example.com"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+
+        server_handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_client_pair_reuses_connections() {
+        use std::{
+            sync::atomic::{AtomicUsize, Ordering},
+            time::Duration,
+        };
+
+        use actix_web::{HttpResponse, HttpServer};
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let server = HttpServer::new(|| App::new().default_service(to(HttpResponse::Ok)))
+            .on_connect({
+                let connections = connections.clone();
+                move |_, _| {
+                    connections.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let client_pair = ClientPair::new(
+            Arc::new(rustls_client_config()),
+            Arc::new(rustls_client_config().disable_sni()),
+            SniMapResolver::from_snimap(&SniMap::new()),
+            ClientOptions {
+                pool_size: 1,
+                pool_idle_timeout: Duration::from_secs(5),
+                ..Default::default()
+            },
+        );
+        for _ in 0..20 {
+            let mut response = client_pair
+                .client_enable_sni()
+                .get(format!("http://{addr}/"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), http::StatusCode::OK);
+            response.body().await.unwrap();
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+        server_handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_slow_body_outlives_response_timeout() {
+        use std::time::Duration;
+
+        use actix_web::{rt::time::sleep, web::Bytes, HttpResponse, HttpServer};
+        use futures::stream;
+
+        let server = HttpServer::new(|| {
+            App::new().default_service(to(|| async {
+                HttpResponse::Ok().streaming(stream::unfold(0, |i| async move {
+                    if i == 3 {
+                        return None;
+                    }
+                    sleep(Duration::from_millis(400)).await;
+                    Some((
+                        Ok::<_, actix_web::Error>(Bytes::from_static(b"chunk")),
+                        i + 1,
+                    ))
+                }))
+            }))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let client_pair = ClientPair::new(
+            Arc::new(rustls_client_config()),
+            Arc::new(rustls_client_config().disable_sni()),
+            SniMapResolver::from_snimap(&SniMap::new()),
+            ClientOptions {
+                response_timeout: Duration::from_millis(500),
+                ..Default::default()
+            },
+        );
+        let mut response = client_pair
+            .client_enable_sni()
+            .get(format!("http://{addr}/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.body().await.unwrap(), "chunkchunkchunk");
+
+        server_handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_sni_fallback() {
+        use std::{collections::HashMap, time::SystemTime};
+
+        use actix_web::{dev::RequestHead, HttpResponse, HttpServer};
+        use futures::stream;
+        use rustls::{
+            client::{ServerCertVerified, ServerCertVerifier},
+            server::{ClientHello, ResolvesServerCert},
+            sign::{any_supported_type, CertifiedKey},
+            Certificate, Error, PrivateKey, ServerConfig, ServerName,
+        };
+
+        use crate::{
+            config::{Sni, Upstream},
+            handler::{forward_by_sni, is_tls_error},
+            tlscert::{cert_generate, Ca},
+        };
+
+        /// like cdns that refuse handshakes without sni
+        struct RequireSni(Arc<CertifiedKey>);
+
+        impl ResolvesServerCert for RequireSni {
+            fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+                client_hello.server_name().map(|_| self.0.clone())
+            }
+        }
+
+        /// the test cert is signed by the snimap ca, which is not a public root
+        struct AcceptAny;
+
+        impl ServerCertVerifier for AcceptAny {
+            fn verify_server_cert(
+                &self,
+                _end_entity: &Certificate,
+                _intermediates: &[Certificate],
+                _server_name: &ServerName,
+                _scts: &mut dyn Iterator<Item = &[u8]>,
+                _ocsp_response: &[u8],
+                _now: SystemTime,
+            ) -> Result<ServerCertVerified, Error> {
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+
+        let cert = cert_generate(
+            &["fallback.test"].into_iter().collect(),
+            &Ca::new().unwrap(),
+        )
+        .await
+        .unwrap();
+        let certified_key = CertifiedKey::new(
+            vec![Certificate(cert.cert)],
+            any_supported_type(&PrivateKey(cert.key)).unwrap(),
+        );
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(RequireSni(Arc::new(certified_key))));
+        let server = HttpServer::new(|| App::new().default_service(to(HttpResponse::Ok)))
+            .workers(1)
+            .bind_rustls("127.0.0.1:0", server_config)
+            .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let snimap = SniMap::builder().disable("fallback.test").build();
+        let resolver = SniMapResolver::from_snimap(&snimap)
+            .with_overrides(&HashMap::from([("fallback.test".to_string(), addr.ip())]));
+        let mut client_config = rustls_client_config();
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(AcceptAny));
+        let client_pair = ClientPair::new(
+            Arc::new(client_config.clone()),
+            Arc::new(client_config.disable_sni()),
+            resolver,
+            ClientOptions::default(),
+        );
+        let upstream = Upstream {
+            port: Some(addr.port()),
+            ..Default::default()
+        };
+        let send = |sni_fallback| {
+            let options = ForwardOptions {
+                sni_fallback,
+                ..Default::default()
+            };
+            let client_pair = &client_pair;
+            let upstream = &upstream;
+            async move {
+                forward_by_sni(
+                    client_pair,
+                    &options,
+                    "id",
+                    &PreferredSni::default(),
+                    "fallback.test",
+                    &Sni::Disable,
+                    upstream,
+                    &RequestHead::default(),
+                    stream::empty(),
+                )
+                .await
+            }
+        };
+
+        let error = send(false).await.unwrap_err();
+        assert!(is_tls_error(&error), "{error}");
+        assert_eq!(send(true).await.unwrap().status(), http::StatusCode::OK);
+
+        server_handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_invalidate_on_certificate_error() {
+        use std::io;
+
+        use awc::error::{ConnectError, SendRequestError};
+        use rustls::internal::msgs::enums::AlertDescription;
+
+        use crate::{
+            config::Sni,
+            error::SnimapError,
+            handler::{invalidate_on_certificate_error, invalidates_on_error},
+        };
+
+        let connect_error = |e: io::Error| {
+            SnimapError::Forward(SendRequestError::Connect(ConnectError::Io(e)).into())
+        };
+        let snimap = SniMap::builder()
+            .override_sni("example.com", "localhost")
+            .build();
+        let resolver = SniMapResolver::from_snimap(&snimap);
+        assert!(resolver.get("localhost").is_some());
+
+        // tcp errors say nothing about who holds the address, neither does a cdn
+        // refusing the sni with an alert or by hanging up
+        for error in [
+            io::ErrorKind::ConnectionRefused.into(),
+            io::ErrorKind::UnexpectedEof.into(),
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                rustls::Error::AlertReceived(AlertDescription::HandshakeFailure),
+            ),
+        ] {
+            invalidate_on_certificate_error(&resolver, "localhost", &connect_error(error));
+            assert!(resolver.snapshot()["localhost"].is_some());
+        }
+
+        // as tokio-rustls reports a certificate for another name
+        invalidate_on_certificate_error(
+            &resolver,
+            "localhost",
+            &connect_error(io::Error::new(
+                io::ErrorKind::InvalidData,
+                rustls::Error::InvalidCertificateData("invalid peer certificate".to_string()),
+            )),
+        );
+        assert_eq!(resolver.snapshot()["localhost"], None);
+
+        let options = |sni_fallback| ForwardOptions {
+            sni_fallback,
+            ..Default::default()
+        };
+        assert!(!invalidates_on_error(&Sni::Disable, &options(false)));
+        assert!(invalidates_on_error(&Sni::Disable, &options(true)));
+        assert!(invalidates_on_error(
+            &Sni::Remain("a.com".to_string()),
+            &options(false)
+        ));
+        assert!(!invalidates_on_error(
+            &Sni::OverrideList(vec!["a.com".to_string(), "b.com".to_string()]),
+            &options(false)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_once() {
+        use actix_web::HttpServer;
+        use futures::StreamExt;
+
+        use crate::handler::Completed;
+
+        let (completed, mut once) = Completed::channel();
+        let completed = Data::new(completed);
+        let server = HttpServer::new(move || {
+            proxy_app(
+                SniMap::new(),
+                system_client_pair(&SniMap::new()),
+                ForwardOptions::default(),
+            )
+            .app_data(completed.clone())
+            .route(HEALTHZ_PATH, healthz_route())
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let server_handle = server.handle();
+        let server = actix_web::rt::spawn(server);
+
+        let client = awc::Client::default();
+        // not proxied, so not counted
+        client
+            .get(format!("http://{addr}{HEALTHZ_PATH}"))
+            .send()
+            .await
+            .unwrap();
+        let response = client
+            .get(format!("http://{addr}/path?q=1"))
+            .insert_header(("host", "example.com"))
+            .insert_header(("x-request-id", "once"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+
+        assert_eq!(
+            once.next().await.unwrap(),
+            "once GET example.com/path?q=1 403 Forbidden"
+        );
+        server_handle.stop(true).await;
+        server.await.unwrap().unwrap();
+        assert!(once.next().await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_sni_line() {
+        use std::{collections::HashMap, net::SocketAddr};
+
+        use crate::{config::Sni, handler::sni_line};
+
+        let snimap = SniMap::builder()
+            .disable("disable.com")
+            .override_sni("override.com", "sni.com")
+            .override_sni("list.com", ["a.com", "b.com"])
+            .remain("remain.com")
+            .build();
+        let resolver = SniMapResolver::from_snimap(&snimap).with_overrides(&HashMap::from([(
+            "sni.com".to_string(),
+            "1.2.3.4".parse().unwrap(),
+        )]));
+
+        let line = |host: &str, connect_host: &str| {
+            let sni = snimap.get(host).unwrap();
+            sni_line("id", host, sni, connect_host, resolver.cached(connect_host))
+        };
+        assert_eq!(
+            line("disable.com", "disable.com"),
+            "id host=disable.com mode=disable sni=- ip=unresolved"
+        );
+        assert_eq!(
+            line("override.com", "sni.com"),
+            "id host=override.com mode=override sni=sni.com ip=1.2.3.4"
+        );
+        assert_eq!(
+            line("list.com", "b.com"),
+            "id host=list.com mode=override sni=b.com ip=unresolved"
+        );
+        assert_eq!(
+            sni_line(
+                "id",
+                "remain.com",
+                &Sni::Remain("remain.com".to_string()),
+                "remain.com",
+                Some(SocketAddr::from(([5, 6, 7, 8], 443)))
+            ),
+            "id host=remain.com mode=remain sni=remain.com ip=5.6.7.8"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_forward_override_list_last_sni() {
+        use std::{collections::HashMap, time::Duration};
+
+        use actix_web::{
+            dev::RequestHead,
+            http::header::{self, HeaderValue},
+        };
+        use futures::stream;
+
+        use crate::{config::Upstream, handler::forward_override_list};
+
+        let snis = ["a.com".to_string(), "b.com".to_string()];
+        let snimap = SniMap::builder()
+            .override_sni("list.com", ["a.com", "b.com"])
+            .build();
+        // TEST-NET-1, never answers
+        let unroutable = "192.0.2.1".parse().unwrap();
+        let resolver = SniMapResolver::from_snimap(&snimap).with_overrides(&HashMap::from([
+            ("a.com".to_string(), unroutable),
+            ("b.com".to_string(), unroutable),
+        ]));
+        let client_pair = ClientPair::new(
+            Arc::new(rustls_client_config()),
+            Arc::new(rustls_client_config().disable_sni()),
+            resolver,
+            ClientOptions {
+                connect_timeout: Duration::from_millis(50),
+                ..Default::default()
+            },
+        );
+        let preferred_sni = PreferredSni::from(&snimap);
+
+        let mut head = RequestHead::default();
+        head.headers
+            .insert(header::HOST, HeaderValue::from_static("list.com"));
+        let (sni, result) = forward_override_list(
+            &client_pair,
+            &ForwardOptions::default(),
+            "id",
+            &preferred_sni,
+            "list.com",
+            &snis,
+            &Upstream::default(),
+            &head,
+            stream::empty(),
+        )
+        .await;
+        assert!(result.is_err());
+        // both failed, so the preferred one went round to `a.com`
+        assert_eq!(sni, "b.com");
+        assert_eq!(preferred_sni.candidates("list.com", 2).next(), Some(0));
+    }
+
+    #[actix_web::test]
+    async fn test_preferred_sni() {
+        use actix_web::http::Method;
+
+        use crate::handler::is_replayable;
+
+        let snimap = SniMap::builder()
+            .override_sni("pixiv.net", ["a.com", "b.com", "c.com"])
+            .build();
+        let preferred_sni = PreferredSni::from(&snimap);
+        assert_eq!(
+            preferred_sni.candidates("pixiv.net", 3).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        preferred_sni.prefer("pixiv.net", 2);
+        assert_eq!(
+            preferred_sni.candidates("pixiv.net", 3).collect::<Vec<_>>(),
+            vec![2, 0, 1]
+        );
+        preferred_sni.prefer("other.net", 1);
+        assert_eq!(
+            preferred_sni.candidates("other.net", 2).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+
+        let get = test::TestRequest::get().to_http_request();
+        assert!(is_replayable(get.head()));
+        let post = test::TestRequest::post()
+            .insert_header(("content-length", "3"))
+            .to_http_request();
+        assert!(!is_replayable(post.head()));
+        let chunked = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .insert_header(("transfer-encoding", "chunked"))
+            .to_http_request();
+        assert!(!is_replayable(chunked.head()));
+    }
+
+    #[actix_web::test]
+    async fn test_append_forwarded_for() {
+        use actix_web::http::header::{self, HeaderMap, HeaderValue};
+
+        use crate::handler::{append_forwarded_for, X_FORWARDED_FOR};
+
+        let mut headers = HeaderMap::new();
+        append_forwarded_for(&mut headers, "1.2.3.4".parse().unwrap()).unwrap();
+        append_forwarded_for(&mut headers, "::1".parse().unwrap()).unwrap();
+        assert_eq!(
+            headers.get(X_FORWARDED_FOR),
+            Some(&HeaderValue::from_static("1.2.3.4, ::1"))
+        );
+        assert_eq!(
+            headers.get(header::FORWARDED),
+            Some(&HeaderValue::from_static("for=1.2.3.4, for=\"[::1]\""))
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.1"));
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_bytes(b"\xff").unwrap());
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.2"));
+        append_forwarded_for(&mut headers, "1.2.3.4".parse().unwrap()).unwrap();
+        assert_eq!(
+            headers.get_all(X_FORWARDED_FOR).collect::<Vec<_>>(),
+            [&HeaderValue::from_static("10.0.0.1, 10.0.0.2, 1.2.3.4")]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_apply_header_rules() {
+        use actix_web::http::header::{self, HeaderMap, HeaderValue};
+
+        use crate::{config::HeaderRules, handler::apply_header_rules};
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::REFERER, HeaderValue::from_static("https://a.com/"));
+        headers.insert(header::DNT, HeaderValue::from_static("0"));
+        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+        let rules = HeaderRules {
+            remove: Some(vec!["referer".to_string()]),
+            set: Some([("DNT".to_string(), "1".to_string())].into()),
+            add: Some([("Accept".to_string(), "text/html".to_string())].into()),
+        };
+        apply_header_rules(&mut headers, &rules).unwrap();
+        assert_eq!(headers.get(header::REFERER), None);
+        assert_eq!(
+            headers.get_all(header::DNT).collect::<Vec<_>>(),
+            vec![&HeaderValue::from_static("1")]
+        );
+        assert_eq!(
+            headers.get_all(header::ACCEPT).collect::<Vec<_>>(),
+            vec![
+                &HeaderValue::from_static("*/*"),
+                &HeaderValue::from_static("text/html")
+            ]
+        );
+
+        let rules = HeaderRules {
+            remove: Some(vec!["bad header".to_string()]),
+            ..Default::default()
+        };
+        assert!(apply_header_rules(&mut headers, &rules).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_rewrite_location() {
+        use crate::handler::rewrite_location;
+
+        let rewrite = |location| rewrite_location(location, "front.example.com", "example.com");
+        assert_eq!(
+            rewrite("https://front.example.com/path?q=1"),
+            Some("https://example.com/path?q=1".to_string())
+        );
+        assert_eq!(
+            rewrite("http://FRONT.example.com:8080"),
+            Some("http://example.com:8080/".to_string())
+        );
+        assert_eq!(rewrite("https://other.example.com/"), None);
+        assert_eq!(rewrite("/relative"), None);
+        assert_eq!(rewrite_location("https://a.com/", "a.com", "a.com"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_accepts_encoding() {
+        use actix_web::http::header::HeaderValue;
+
+        use crate::handler::accepts_encoding;
+
+        let accept = HeaderValue::from_static("gzip, deflate;q=0.5, br;q=0");
+        assert!(accepts_encoding(Some(&accept), "gzip"));
+        assert!(accepts_encoding(Some(&accept), "GZIP"));
+        assert!(accepts_encoding(Some(&accept), "deflate"));
+        assert!(!accepts_encoding(Some(&accept), "br"));
+        assert!(!accepts_encoding(None, "br"));
+        assert!(accepts_encoding(None, "identity"));
+        assert!(accepts_encoding(
+            Some(&HeaderValue::from_static("*")),
+            "zstd"
+        ));
+    }
 }
@@ -0,0 +1,61 @@
+#![feature(result_option_inspect)]
+//! Reverse proxy that rewrites or strips the TLS SNI of upstream requests.
+//!
+//! Embedding the proxy into your own actix app:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use actix_web::{
+//!     test,
+//!     web::{to, Data},
+//!     App,
+//! };
+//! use snimap::{
+//!     handler::{ClientOptions, ForwardOptions, ForwardStats, PreferredSni},
+//!     reverse_proxy, rustls_client_config, ClientPair, DisableSni, SniMap, SniMapResolver,
+//! };
+//!
+//! # actix_web::rt::System::new().block_on(async {
+//! let snimap = SniMap::builder()
+//!     .override_sni("pixiv.net", "www.fanbox.cc")
+//!     .build();
+//! let resolver = SniMapResolver::from_snimap(&snimap);
+//! let app = App::new()
+//!     .app_data(Data::new(ForwardStats::from(&snimap)))
+//!     .app_data(Data::new(PreferredSni::from(&snimap)))
+//!     .app_data(Data::new(snimap))
+//!     .app_data(Data::new(ClientPair::new(
+//!         Arc::new(rustls_client_config()),
+//!         Arc::new(rustls_client_config().disable_sni()),
+//!         resolver,
+//!         ClientOptions::default(),
+//!     )))
+//!     .app_data(Data::new(ForwardOptions::default()))
+//!     .default_service(to(reverse_proxy));
+//!
+//! let app = test::init_service(app).await;
+//! let req = test::TestRequest::get()
+//!     .insert_header(("host", "example.com"))
+//!     .to_request();
+//! assert_eq!(test::call_service(&app, req).await.status(), 403);
+//! # });
+//! ```
+
+mod anyway;
+pub mod config;
+mod dirs;
+pub mod error;
+pub mod handler;
+pub mod logging;
+pub mod resolver;
+pub mod tlscert;
+pub mod utils;
+
+pub use config::{Sni, SniMap};
+pub use handler::{reverse_proxy, ClientPair};
+pub use resolver::SniMapResolver;
+pub use tlscert::{
+    cert_generate, rustls_client_config, rustls_server_config, Ca, DebugTls, DisableSni, EnableH2,
+    WithAlpn,
+};
@@ -0,0 +1,55 @@
+/// startup, shutdown and anything about the proxy itself
+pub const PROXY: &str = "proxy";
+/// resolver cache and prewarming
+pub const RESOLVER: &str = "resolver";
+/// requests sent upstream
+pub const FORWARD: &str = "forward";
+/// single dns or ipaddress.com lookups
+pub const LOOKUP: &str = "lookup";
+/// one debug line per request with its sni mode and upstream address,
+/// off unless asked for with `RUST_LOG=sni=debug` or `--verbose`
+pub const SNI: &str = "sni";
+
+const TARGETS: [&str; 4] = [PROXY, RESOLVER, FORWARD, LOOKUP];
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum Verbosity {
+    /// errors only
+    Quiet,
+    /// info from snimap, errors from dependencies
+    #[default]
+    Normal,
+    /// everything from snimap, debug from dependencies as well
+    Verbose,
+}
+
+impl Verbosity {
+    /// `RUST_LOG` filter for this verbosity
+    pub fn filter(self) -> String {
+        // a bare target name enables every level of it
+        let (base, level) = match self {
+            Verbosity::Quiet => return "error".to_string(),
+            Verbosity::Normal => ("error", "=info"),
+            Verbosity::Verbose => ("debug", ""),
+        };
+        [base.to_string()]
+            .into_iter()
+            .chain(TARGETS.iter().map(|target| format!("{target}{level}")))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_verbosity_filter() {
+    assert_eq!(Verbosity::Quiet.filter(), "error");
+    assert_eq!(
+        Verbosity::Normal.filter(),
+        "error,proxy=info,resolver=info,forward=info,lookup=info"
+    );
+    assert_eq!(
+        Verbosity::Verbose.filter(),
+        "debug,proxy,resolver,forward,lookup"
+    );
+}
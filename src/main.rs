@@ -1,49 +1,239 @@
-#![feature(result_option_inspect)]
-
-use std::{collections::HashSet, env, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
 use actix_web::{
+    middleware::{Compress, Condition},
     web::{to, Data},
     App, HttpServer,
 };
-use anyway::AnyResult;
 use async_ctrlc::CtrlC;
-use config::{Config, SniMap};
-use futures::try_join;
-use handler::{reverse_proxy, ClientPair};
-use resolver::SniMapResolver;
-use tlscert::{cert_generate, rustls_client_config, rustls_server_config, DisableSni};
-use utils::edit_hosts;
-
-mod anyway;
-mod config;
-mod dirs;
-mod handler;
-mod resolver;
-mod tlscert;
-mod utils;
+use cli::{Args, USAGE};
+use futures::{
+    future::{join_all, select, Either},
+    try_join, StreamExt,
+};
+use snimap::{
+    config::{Config, Mapping, ResolveVia, Sni, SniMap, Switchable},
+    error::{SnimapError, SnimapResult},
+    handler::{
+        check_host, healthz_route, reresolve_route, resolver_cache_route, reverse_proxy,
+        ClientOptions, ClientPair, Completed, ForwardOptions, ForwardStats, PreferredSni,
+        HEALTHZ_PATH, RERESOLVE_PATH, RESOLVER_CACHE_PATH,
+    },
+    logging::{Verbosity, PROXY, RESOLVER},
+    ok,
+    resolver::SniMapResolver,
+    tlscert::{
+        cert_generate, export_ca, rustls_client_config, rustls_client_config_with_versions,
+        rustls_server_config, tls_versions, Ca, DisableSni, EnableH2, SanDiff, TlsVersions,
+        VerifyOptions, WithAlpn, WithSessionCache, WithVerifyOptions, SESSION_CACHE_SIZE,
+    },
+    utils::{read_static_hosts, restore_hosts_hint, sync_hosts},
+};
+
+mod cli;
 
 #[actix_web::main]
-async fn main() -> AnyResult<()> {
-    init_logger();
+async fn main() -> SnimapResult<()> {
+    let args = Args::parse()?;
 
-    let snimap = SniMap::from(Config::from_default_file().await?);
+    init_logger(args.verbosity);
 
-    let snimap_resolver = SniMapResolver::from_snimap(&snimap);
+    if args.help {
+        println!("{USAGE}");
+        return ok!();
+    }
 
-    let snimap_data = Data::new(SniMap::from(Config::from_default_file().await?));
+    if let Some(path) = &args.export_ca {
+        export_ca(path, &Ca::from_default_dir()?)?;
+        log::info!(target: PROXY, "export ca certificate to {:?}", path);
+        return ok!();
+    }
 
-    let hostnames = snimap_data.hostnames();
+    if args.generate_config {
+        let config = match &args.preset {
+            Some(names) => Config::preset(names)?,
+            None => Config::default(),
+        };
+        let path = config.create_default_file()?;
+        println!(
+            "wrote {} with groups: {}",
+            path.display(),
+            config.group_names().join(", ")
+        );
+        return ok!();
+    }
+
+    let config = Config::from_default_file().await?.expand_env()?;
+
+    let address_overrides = config.address_overrides();
+
+    let remain_system_lookup = config.remain_system_lookup();
+
+    let prefer_ipv6 = config.prefer_ipv6();
+
+    let lookup_concurrency = config.lookup_concurrency();
+
+    let static_hosts = match config.hosts_file_lookup() {
+        true => read_static_hosts(),
+        false => HashMap::new(),
+    };
+
+    if let Some(hosts) = &args.resolve {
+        let mut snimap = SniMap::from(config);
+        for host in hosts {
+            if snimap.get(host).is_none() {
+                snimap.merge(Mapping::new(host));
+            }
+        }
+        let hosts = hosts.iter().map(String::as_str).collect::<Vec<_>>();
+        let reports = SniMapResolver::from_snimap(&snimap)
+            .with_remain_system_lookup(remain_system_lookup)
+            .with_prefer_ipv6(prefer_ipv6)
+            .with_lookup_limit(lookup_concurrency)
+            .with_overrides(&address_overrides)
+            .with_hosts_file(&static_hosts)
+            .lookup_report(&hosts);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&reports).map_err(|e| SnimapError::Resolve(e.into()))?
+        );
+        return ok!();
+    }
+
+    if args.print_map {
+        print_map(&SniMap::from(config));
+        return ok!();
+    }
+
+    let forward_options = Data::new(ForwardOptions {
+        passthrough_unknown: args.passthrough_unknown,
+        follow_redirects: args
+            .follow_redirects
+            .unwrap_or_else(|| config.follow_redirects()),
+        ..ForwardOptions::from(&config)
+    });
+
+    let recompress = forward_options.decompress;
 
-    edit_hosts(&hostnames).await?;
+    let client_options = ClientOptions::from(&config);
 
-    let cert = cert_generate(&hostnames).await?;
+    let server_tls_versions = match config.min_tls_version() {
+        Some(min_version) => parse_tls_versions(min_version)?,
+        None => rustls::DEFAULT_VERSIONS,
+    };
 
+    let client_config = match config.upstream_min_tls_version() {
+        Some(min_version) => rustls_client_config_with_versions(parse_tls_versions(min_version)?),
+        None => rustls_client_config(),
+    };
+
+    let client_config = match (config.alpn(), config.http2()) {
+        (Some(alpn), _) => client_config.with_alpn(alpn),
+        (None, true) => client_config.enable_h2(),
+        (None, false) => client_config,
+    };
+
+    let verify_options = VerifyOptions {
+        log_peer_cert: args.debug_tls,
+        request_scts: config.request_scts(),
+    };
+
+    let client_config = match verify_options == VerifyOptions::default() {
+        true => client_config,
+        false => client_config.with_verify_options(verify_options),
+    };
+
+    // separate session caches, a session from a handshake with sni is not offered without it
     let (client_config_enable_sni, client_config_disable_sni) = (
-        Arc::new(rustls_client_config()),
-        Arc::new(rustls_client_config().disable_sni()),
+        Arc::new(client_config.clone().with_session_cache(SESSION_CACHE_SIZE)),
+        Arc::new(
+            client_config
+                .disable_sni()
+                .with_session_cache(SESSION_CACHE_SIZE),
+        ),
     );
 
+    let no_hosts = skip_hosts(&args, &config);
+
+    if !config.enabled() {
+        log::warn!(
+            target: PROXY,
+            "disabled by `enable = false`, nothing is redirected and hosts is left untouched"
+        );
+    }
+
+    let snimap_data = Data::new(SniMap::from(config));
+
+    let forward_stats = Data::new(ForwardStats::from(&**snimap_data));
+
+    let preferred_sni = Data::new(PreferredSni::from(&**snimap_data));
+
+    let snimap_resolver = SniMapResolver::from_snimap(&snimap_data)
+        .with_remain_system_lookup(remain_system_lookup)
+        .with_hosts_edited(!no_hosts && !args.check)
+        .with_prefer_ipv6(prefer_ipv6)
+        .with_lookup_limit(lookup_concurrency)
+        .with_passthrough_unknown(args.passthrough_unknown)
+        .with_overrides(&address_overrides)
+        .with_hosts_file(&static_hosts);
+
+    if args.check {
+        let client_pair = ClientPair::new(
+            client_config_enable_sni,
+            client_config_disable_sni,
+            snimap_resolver,
+            client_options,
+        );
+        return check(&snimap_data, &client_pair).await;
+    }
+
+    check_resolve_via(&snimap_data, no_hosts)?;
+
+    let hostnames = snimap_data.hostnames();
+
+    let cert = if args.no_tls {
+        log::info!(target: PROXY, "tls disabled, skip cert generation");
+        None
+    } else {
+        let cert = cert_generate(&hostnames, &Ca::from_default_dir()?).await?;
+        let san_diff = SanDiff::new(&cert.alt_dnsnames, &hostnames);
+        if !san_diff.missing.is_empty() {
+            log::warn!(
+                target: PROXY,
+                "server cert does not cover {:?}, restart to regenerate it",
+                san_diff.missing
+            );
+        }
+        Some(cert)
+    };
+
+    // after the cert, a missing ca key should not leave hosts edited
+    sync_hosts(&snimap_data.hostnames_with_group(), no_hosts).await?;
+
+    if args.prewarm {
+        // failures are cached for a short while, then looked up again on request
+        for host in snimap_resolver.prewarm().await {
+            log::warn!(target: RESOLVER, "failed to prewarm {host}");
+        }
+    }
+
+    let (stats, resolver) = (forward_stats.clone(), snimap_resolver.clone());
+
+    let admin = args.admin;
+
+    let (completed, once) = match args.once {
+        true => {
+            let (completed, once) = Completed::channel();
+            (Some(Data::new(completed)), Some(once))
+        }
+        false => (None, None),
+    };
+
     let server = HttpServer::new(move || {
         App::new()
             .app_data(snimap_data.clone())
@@ -51,31 +241,74 @@ async fn main() -> AnyResult<()> {
                 client_config_enable_sni.clone(),
                 client_config_disable_sni.clone(),
                 snimap_resolver.clone(),
+                client_options,
             )))
+            .app_data(forward_options.clone())
+            .app_data(forward_stats.clone())
+            .app_data(preferred_sni.clone())
+            .app_data(Data::new(snimap_resolver.clone()))
+            .wrap(Condition::new(recompress, Compress::default()))
+            .route(HEALTHZ_PATH, healthz_route())
+            .configure(|cfg| {
+                if admin {
+                    cfg.route(RESOLVER_CACHE_PATH, resolver_cache_route())
+                        .route(RERESOLVE_PATH, reresolve_route());
+                }
+                if let Some(completed) = &completed {
+                    cfg.app_data(completed.clone());
+                }
+            })
             .default_service(to(reverse_proxy))
-    })
-    .bind_rustls("127.0.0.1:443", rustls_server_config(cert)?)?
-    .disable_signals()
-    .client_request_timeout(Duration::from_secs(30))
-    .client_disconnect_timeout(Duration::from_secs(30))
-    .run();
+    });
+
+    let (server, listen_addr) = match cert {
+        Some(cert) => (
+            server.bind_rustls(
+                "127.0.0.1:443",
+                rustls_server_config(cert, server_tls_versions)?,
+            ),
+            "127.0.0.1:443",
+        ),
+        None => (server.bind("127.0.0.1:80"), "127.0.0.1:80"),
+    };
+
+    let server = server
+        .map_err(|e| SnimapError::Bind(e.into()))?
+        .disable_signals()
+        .client_request_timeout(Duration::from_secs(30))
+        .client_disconnect_timeout(Duration::from_secs(30))
+        .run();
 
     let server_handle = server.handle();
 
     try_join!(
         async {
-            CtrlC::new()
-                .expect("Failed to install Ctrl-C handler")
-                .await;
-            log::info!(target: "proxy", "waiting for server stop ...");
+            let ctrl_c = CtrlC::new().expect("Failed to install Ctrl-C handler");
+            match once {
+                Some(mut once) => {
+                    if let Either::Left((Some(summary), _)) = select(once.next(), ctrl_c).await {
+                        println!("{summary}");
+                    }
+                }
+                None => ctrl_c.await,
+            }
+            log::info!(target: PROXY, "waiting for server stop ...");
             server_handle.stop(true).await;
-            edit_hosts(&HashSet::new()).await?;
-            log::info!(target: "proxy", "restore hosts");
+            log_stats(&stats, &resolver);
+            // keep shutting down, the hosts block can be removed by hand
+            match sync_hosts(&HashMap::new(), no_hosts).await {
+                Ok(()) if !no_hosts => log::info!(target: PROXY, "restore hosts"),
+                Ok(()) => {}
+                Err(e) => {
+                    log::error!(target: PROXY, "failed to restore hosts: {e}");
+                    log::error!(target: PROXY, "restore it manually: {}", restore_hosts_hint());
+                }
+            }
             ok!()
         },
         async {
-            log::info!(target: "proxy", "start server on :443");
-            server.await?;
+            log::info!(target: PROXY, "start server on {listen_addr}");
+            server.await.map_err(|e| SnimapError::Bind(e.into()))?;
             ok!()
         }
     )?;
@@ -83,10 +316,153 @@ async fn main() -> AnyResult<()> {
     ok!()
 }
 
-fn init_logger() {
+fn print_map(snimap: &SniMap) {
+    let (disabled, overridden, remain) = snimap.by_mode();
+    println!("disable ({}):", disabled.len());
+    disabled.iter().for_each(|host| println!("  {host}"));
+    println!("override ({}):", overridden.len());
+    overridden
+        .iter()
+        .for_each(|(host, snis)| println!("  {host} -> {}", snis.join(", ")));
+    println!("remain ({}):", remain.len());
+    remain.iter().for_each(|host| println!("  {host}"));
+}
+
+async fn check(snimap: &SniMap, client_pair: &ClientPair) -> SnimapResult<()> {
+    let mut hostnames = snimap.hostnames().into_iter().collect::<Vec<_>>();
+    hostnames.sort_unstable();
+    let results = join_all(
+        hostnames
+            .iter()
+            .map(|host| check_host(snimap, client_pair, host)),
+    )
+    .await;
+    let mut failed = 0;
+    for (host, result) in hostnames.iter().zip(results) {
+        let mode = snimap.get(host).map_or("", Sni::mode_str);
+        match result {
+            Ok(status) => println!("ok    {mode:<8}  {host}  {status}"),
+            Err(e) => {
+                failed += 1;
+                println!("fail  {mode:<8}  {host}  {e}");
+            }
+        }
+    }
+    println!("{} ok, {failed} failed", hostnames.len() - failed);
+    if failed > 0 {
+        return Err(SnimapError::Forward(
+            format!("{failed} of {} hosts failed", hostnames.len()).into(),
+        ));
+    }
+    ok!()
+}
+
+/// a disabled config redirects nothing, so there is nothing to write to hosts either
+fn skip_hosts(args: &Args, config: &Config) -> bool {
+    args.no_hosts || !config.enabled()
+}
+
+/// the system resolver reads the hosts file, where snimap points every mapped
+/// hostname at itself, so `resolve_via = "system"` needs `--no-hosts`
+fn check_resolve_via(snimap: &SniMap, no_hosts: bool) -> SnimapResult<()> {
+    if no_hosts {
+        return ok!();
+    }
+    let mut hosts = snimap
+        .hostnames()
+        .into_iter()
+        .filter(|host| snimap.upstream(host).resolve_via == Some(ResolveVia::System))
+        .collect::<Vec<_>>();
+    if hosts.is_empty() {
+        return ok!();
+    }
+    hosts.sort_unstable();
+    Err(SnimapError::Config(
+        format!("`resolve_via = \"system\"` on {hosts:?} requires `--no-hosts`").into(),
+    ))
+}
+
+fn log_stats(forward_stats: &ForwardStats, resolver: &SniMapResolver) {
+    let resolver_stats = resolver.stats();
+    log::info!(
+        target: PROXY,
+        "forwarded {} requests, resolved {} from cache and {} by lookup",
+        forward_stats.forwarded.load(Ordering::Relaxed),
+        resolver_stats.cache_hits.load(Ordering::Relaxed),
+        resolver_stats.lookups.load(Ordering::Relaxed),
+    );
+    for (host, count) in forward_stats.per_host_sorted() {
+        log::info!(target: PROXY, "  {host}: {count}");
+    }
+}
+
+fn parse_tls_versions(min_version: &str) -> SnimapResult<TlsVersions> {
+    tls_versions(min_version).ok_or_else(|| {
+        SnimapError::Config(format!("unsupported tls version {min_version:?}").into())
+    })
+}
+
+/// `--quiet` and `--verbose` win over `RUST_LOG`, which wins over the default
+fn init_logger(verbosity: Option<Verbosity>) {
     let log_name = "RUST_LOG";
-    if env::var(log_name).is_err() {
-        env::set_var(log_name, "error,proxy,resolver,forward,lookup");
+    match verbosity {
+        Some(verbosity) => env::set_var(log_name, verbosity.filter()),
+        None if env::var(log_name).is_err() => {
+            env::set_var(log_name, Verbosity::default().filter())
+        }
+        None => {}
     }
     pretty_env_logger::init_custom_env(log_name);
 }
+
+#[cfg(test)]
+mod tests {
+    use snimap::config::{Config, SniMap, Switchable};
+
+    use crate::{check_resolve_via, cli::Args, skip_hosts};
+
+    #[test]
+    fn disabled_config_skips_hosts() {
+        let mut config = Config::default();
+        assert!(!skip_hosts(&Args::default(), &config));
+
+        *config.enable_mut() = Some(false);
+        assert!(skip_hosts(&Args::default(), &config));
+
+        let no_hosts = Args {
+            no_hosts: true,
+            ..Default::default()
+        };
+        assert!(skip_hosts(&no_hosts, &Config::default()));
+    }
+
+    #[test]
+    fn resolve_via_system_requires_no_hosts() {
+        let config: Config = toml::from_str(
+            r#"
+[[groups]]
+name = "system"
+resolve_via = "system"
+
+[[groups.mappings]]
+hostname = "system.com"
+
+[[groups]]
+name = "default"
+
+[[groups.mappings]]
+hostname = "remain.com"
+"#,
+        )
+        .unwrap();
+        let snimap = SniMap::from(config);
+        assert!(check_resolve_via(&snimap, false)
+            .unwrap_err()
+            .to_string()
+            .contains("[\"system.com\"]"));
+        assert!(check_resolve_via(&snimap, true).is_ok());
+
+        let snimap = SniMap::builder().remain("remain.com").build();
+        assert!(check_resolve_via(&snimap, false).is_ok());
+    }
+}
@@ -1,80 +1,352 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
 use actix_tls::connect::Resolve;
+use actix_web::rt::task::spawn_blocking;
 use dns_lookup::lookup_host;
-use futures::future::LocalBoxFuture;
+use futures::future::{join_all, LocalBoxFuture};
 use once_cell::sync::{Lazy, OnceCell};
 use regex::Regex;
+use serde_derive::Serialize;
 
-use crate::{anyway::AnyResult, config::SniMap};
+use crate::{
+    config::{ResolveVia, Sni, SniMap},
+    error::{SnimapError, SnimapResult},
+    logging::{LOOKUP, RESOLVER},
+};
 
 static RE_CAPTURE_IP: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"ipaddress.com/ipv4/((\d+\.){3}\d+)").unwrap());
 
-fn ip_lookup_on_ipaddress_com<S: AsRef<str>>(host: S) -> AnyResult<String> {
-    attohttpc::post("https://www.ipaddress.com/ip-lookup")
-        .header("Referer", "https://www.ipaddress.com/ip-lookup")
+const IPADDRESS_COM_LOOKUP: &str = "https://www.ipaddress.com/ip-lookup";
+
+/// covers connecting and reading, a hung lookup must not stall the connection forever
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn ip_lookup_on_ipaddress_com<S: AsRef<str>>(host: S) -> SnimapResult<String> {
+    ip_lookup_on(IPADDRESS_COM_LOOKUP, host.as_ref(), LOOKUP_TIMEOUT)
+}
+
+fn ip_lookup_on(url: &str, host: &str, timeout: Duration) -> SnimapResult<String> {
+    attohttpc::post(url)
+        .header("Referer", IPADDRESS_COM_LOOKUP)
         .header("Accept-Encoding", "br")
-        .form(&[("host", host.as_ref())])?
+        .timeout(timeout)
+        .form(&[("host", host)])?
         .send()?
         .text()
         .map_err(Into::into)
 }
 
-fn capture_ip_from_html_plain<S: AsRef<str>>(html: S) -> AnyResult<IpAddr> {
+fn capture_ip_from_html_plain<S: AsRef<str>>(html: S) -> SnimapResult<IpAddr> {
     RE_CAPTURE_IP
         .captures(html.as_ref())
-        .ok_or("err in capture_ip_from_html_plain: no match is found")?
+        .ok_or_else(|| {
+            SnimapError::Resolve("err in capture_ip_from_html_plain: no match is found".into())
+        })?
         .get(1)
-        .ok_or("err in capture_ip_from_html_plain: this group didn't participate in the match")?
+        .ok_or_else(|| {
+            SnimapError::Resolve(
+                "err in capture_ip_from_html_plain: this group didn't participate in the match"
+                    .into(),
+            )
+        })?
         .as_str()
         .parse::<IpAddr>()
         .map_err(Into::into)
 }
 
-enum ResolveResult<LateInitAddr = OnceCell<SocketAddr>> {
+/// `1.2.3.4`, `::1` or `[::1]` resolve to themselves
+fn ip_literal(host: &str) -> Option<SocketAddr> {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok()
+        .map(|ip_addr| SocketAddr::new(ip_addr, 443))
+}
+
+fn lookup_system(host: &str) -> Result<Vec<IpAddr>, String> {
+    lookup_host(host).map_err(|e| e.to_string())
+}
+
+/// the system resolver minus loopback and unspecified answers, which is what
+/// the hosts file gives every hostname snimap maps
+fn lookup_system_remote(host: &str, prefer_ipv6: bool) -> Result<SocketAddr, String> {
+    let mut ip_addrs = lookup_system(host)?;
+    ip_addrs.retain(|ip_addr| !ip_addr.is_loopback() && !ip_addr.is_unspecified());
+    pick_ip_addr(ip_addrs, prefer_ipv6)
+        .map(|ip_addr| SocketAddr::new(ip_addr, 443))
+        .ok_or_else(|| "only loopback addresses found".to_string())
+}
+
+/// the first address, or the first ipv6 one if preferred and there is any
+fn pick_ip_addr(ip_addrs: Vec<IpAddr>, prefer_ipv6: bool) -> Option<IpAddr> {
+    ip_addrs
+        .iter()
+        .find(|ip_addr| prefer_ipv6 && ip_addr.is_ipv6())
+        .or_else(|| ip_addrs.first())
+        .copied()
+}
+
+fn lookup_ipaddress_com(host: &str) -> Result<SocketAddr, String> {
+    ip_lookup_on_ipaddress_com(host)
+        .and_then(capture_ip_from_html_plain)
+        .map(|ip_addr| SocketAddr::new(ip_addr, 443))
+        .map_err(|e| e.to_string())
+}
+
+/// failed lookups are not retried before this, every request would hit ipaddress.com otherwise
+const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+/// the resolved address, or the most recent failure while it is younger than `NEGATIVE_TTL`
+#[derive(Default)]
+struct LookupCell {
+    socket_addr: OnceCell<SocketAddr>,
+    failure: Mutex<Option<(Instant, String)>>,
+}
+
+impl LookupCell {
+    fn with_value(socket_addr: SocketAddr) -> Self {
+        Self {
+            socket_addr: OnceCell::with_value(socket_addr),
+            failure: Default::default(),
+        }
+    }
+
+    fn get(&self) -> Option<SocketAddr> {
+        self.socket_addr.get().copied()
+    }
+
+    fn recent_failure(&self, ttl: Duration) -> Option<String> {
+        match &*self.failure.lock().unwrap() {
+            Some((failed_at, error)) if failed_at.elapsed() < ttl => Some(error.clone()),
+            _ => None,
+        }
+    }
+
+    fn fail(&self, error: &str) {
+        *self.failure.lock().unwrap() = Some((Instant::now(), error.to_string()));
+    }
+
+    fn take(&mut self) -> bool {
+        let failed = self.failure.get_mut().unwrap().take().is_some();
+        self.socket_addr.take().is_some() || failed
+    }
+}
+
+enum ResolveResult<LateInitAddr = LookupCell> {
     CGetAddrInfo(LateInitAddr),
+    /// `resolve_via = "system"`, never the loopback snimap put in the hosts file
+    System(LateInitAddr),
     WwwIpaddressCom(LateInitAddr),
+    /// ipaddress.com only when the system resolver has nothing but loopback
+    CGetAddrInfoOrWwwIpaddressCom(LateInitAddr),
+    Pinned(LateInitAddr),
+    /// pinned by the user in the hosts file, outside the block snimap manages
+    HostsFile(LateInitAddr),
 }
 
 impl ResolveResult {
-    pub fn get_or_init(&self, host: &str) -> Option<SocketAddr> {
+    pub fn source(&self) -> &'static str {
         match self {
-            ResolveResult::CGetAddrInfo(socket_addr) => socket_addr.get_or_try_init(|| {
-                lookup_host(host)
-                    .map_err(|e| e.to_string())
-                    .and_then(|ip_addrs| {
-                        ip_addrs
-                            .into_iter()
-                            .next()
-                            .map(|ip_addr| SocketAddr::new(ip_addr, 443))
-                            .ok_or_else(|| {
-                                "no socket_addr found in return value of `lookup_host` function"
-                                    .to_string()
-                            })
-                    })
-                    .inspect(|socket_addr| log::info!(target: "lookup", "{host} -> {socket_addr}"))
-            }),
-            ResolveResult::WwwIpaddressCom(socket_addr) => socket_addr.get_or_try_init(|| {
-                ip_lookup_on_ipaddress_com(host)
-                    .and_then(capture_ip_from_html_plain)
-                    .map(|ip_addr| SocketAddr::new(ip_addr, 443))
-                    .map_err(|e| e.to_string())
-                    .inspect(|socket_addr| log::info!(target: "lookup", "{host} -> {socket_addr}"))
+            ResolveResult::CGetAddrInfo(_) => "CGetAddrInfo",
+            ResolveResult::System(_) => "System",
+            ResolveResult::WwwIpaddressCom(_) => "WwwIpaddressCom",
+            ResolveResult::CGetAddrInfoOrWwwIpaddressCom(_) => "CGetAddrInfoOrWwwIpaddressCom",
+            ResolveResult::Pinned(_) => "Pinned",
+            ResolveResult::HostsFile(_) => "HostsFile",
+        }
+    }
+
+    /// the address resolved so far, never triggers a lookup
+    fn cached(&self) -> Option<SocketAddr> {
+        match self {
+            ResolveResult::CGetAddrInfo(socket_addr)
+            | ResolveResult::System(socket_addr)
+            | ResolveResult::WwwIpaddressCom(socket_addr)
+            | ResolveResult::CGetAddrInfoOrWwwIpaddressCom(socket_addr)
+            | ResolveResult::Pinned(socket_addr)
+            | ResolveResult::HostsFile(socket_addr) => socket_addr.get(),
+        }
+    }
+
+    /// forget the resolved address so the next `get_or_init` looks it up again,
+    /// pinned addresses have nothing to look up and are kept
+    fn reset(&mut self) -> bool {
+        match self {
+            ResolveResult::CGetAddrInfo(socket_addr)
+            | ResolveResult::System(socket_addr)
+            | ResolveResult::WwwIpaddressCom(socket_addr)
+            | ResolveResult::CGetAddrInfoOrWwwIpaddressCom(socket_addr) => socket_addr.take(),
+            ResolveResult::Pinned(_) | ResolveResult::HostsFile(_) => false,
+        }
+    }
+
+    pub fn get_or_init(
+        &self,
+        host: &str,
+        stats: &ResolverStats,
+        limit: &LookupLimit,
+        prefer_ipv6: bool,
+    ) -> Option<SocketAddr> {
+        if let Some(socket_addr) = ip_literal(host) {
+            return Some(socket_addr);
+        }
+        match self {
+            ResolveResult::CGetAddrInfo(socket_addr) => {
+                single_flight(socket_addr, stats, limit, || {
+                    lookup_system(host)
+                        .and_then(|ip_addrs| {
+                            pick_ip_addr(ip_addrs, prefer_ipv6)
+                                .map(|ip_addr| SocketAddr::new(ip_addr, 443))
+                                .ok_or_else(|| {
+                                    "no socket_addr found in return value of `lookup_host` function"
+                                        .to_string()
+                                })
+                        })
+                        .inspect(
+                            |socket_addr| log::info!(target: LOOKUP, "{host} -> {socket_addr}"),
+                        )
+                })
+            }
+            ResolveResult::System(socket_addr) => single_flight(socket_addr, stats, limit, || {
+                lookup_system_remote(host, prefer_ipv6)
+                    .inspect(|socket_addr| log::info!(target: LOOKUP, "{host} -> {socket_addr}"))
             }),
+            ResolveResult::WwwIpaddressCom(socket_addr) => {
+                single_flight(socket_addr, stats, limit, || {
+                    lookup_ipaddress_com(host).inspect(
+                        |socket_addr| log::info!(target: LOOKUP, "{host} -> {socket_addr}"),
+                    )
+                })
+            }
+            ResolveResult::CGetAddrInfoOrWwwIpaddressCom(socket_addr) => {
+                single_flight(socket_addr, stats, limit, || {
+                    // the hosts file points every mapped hostname back at snimap
+                    lookup_system_remote(host, prefer_ipv6)
+                        .or_else(|e| {
+                            log::debug!(target: LOOKUP, "{host} -> {e}, try ipaddress.com");
+                            lookup_ipaddress_com(host)
+                        })
+                        .inspect(
+                            |socket_addr| log::info!(target: LOOKUP, "{host} -> {socket_addr}"),
+                        )
+                })
+            }
+            ResolveResult::Pinned(socket_addr) | ResolveResult::HostsFile(socket_addr) => {
+                single_flight(socket_addr, stats, limit, || {
+                    Err("pinned address is not set".to_string())
+                })
+            }
         }
-        .inspect_err(|e| log::error!(target: "lookup", "{host} -> failed to lookup: {e}"))
+        .inspect_err(|e| log::error!(target: LOOKUP, "{host} -> failed to lookup: {e}"))
         .ok()
-        .cloned()
     }
 }
 
+/// at most one `lookup` runs per cell at a time, concurrent callers wait for
+/// its result instead of firing their own, a recent failure is returned as is
+fn single_flight<F>(
+    cell: &LookupCell,
+    stats: &ResolverStats,
+    limit: &LookupLimit,
+    lookup: F,
+) -> Result<SocketAddr, String>
+where
+    F: FnOnce() -> Result<SocketAddr, String>,
+{
+    let mut looked_up = false;
+    let result = cell
+        .socket_addr
+        .get_or_try_init(|| {
+            if let Some(error) = cell.recent_failure(NEGATIVE_TTL) {
+                return Err(format!("{error} (cached)"));
+            }
+            looked_up = true;
+            limit.run(lookup).inspect_err(|e| cell.fail(e))
+        })
+        .copied();
+    match looked_up {
+        true => &stats.lookups,
+        false => &stats.cache_hits,
+    }
+    .fetch_add(1, Ordering::Relaxed);
+    result
+}
+
+/// caps lookups in flight across all hosts, they run on blocking threads
+/// so a plain counting semaphore is enough
+#[derive(Debug)]
+pub struct LookupLimit {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl LookupLimit {
+    pub const DEFAULT: usize = 8;
+
+    pub fn new(max: usize) -> Self {
+        Self {
+            available: Mutex::new(max.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    fn run<T>(&self, f: impl FnOnce() -> T) -> T {
+        struct Permit<'a>(&'a LookupLimit);
+
+        impl Drop for Permit<'_> {
+            fn drop(&mut self) {
+                *self.0.available.lock().unwrap() += 1;
+                self.0.released.notify_one();
+            }
+        }
+
+        let mut available = self
+            .released
+            .wait_while(self.available.lock().unwrap(), |n| *n == 0)
+            .unwrap();
+        *available -= 1;
+        drop(available);
+        let _permit = Permit(self);
+        f()
+    }
+}
+
+impl Default for LookupLimit {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT)
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct LookupReport {
+    pub host: String,
+    pub ip: Option<IpAddr>,
+    pub source: Option<&'static str>,
+}
+
+#[derive(Default, Debug)]
+pub struct ResolverStats {
+    pub cache_hits: AtomicU64,
+    pub lookups: AtomicU64,
+}
+
 pub struct SniMapResolver {
-    cache: Arc<HashMap<String, ResolveResult>>,
+    /// the lock is only taken for writing by `invalidate`, which waits for a
+    /// lookup in progress, and readers arriving meanwhile may wait behind it
+    cache: Arc<HashMap<String, RwLock<ResolveResult>>>,
+    stats: Arc<ResolverStats>,
+    limit: Arc<LookupLimit>,
+    prefer_ipv6: bool,
+    passthrough_unknown: bool,
 }
 
 impl SniMapResolver {
@@ -85,49 +357,248 @@ impl SniMapResolver {
                     .hostnames()
                     .iter()
                     .map(|s| {
+                        let resolve_result = match (snimap.upstream(s).resolve_via, snimap.get(s)) {
+                            (Some(ResolveVia::System), _) => {
+                                ResolveResult::System(LookupCell::default())
+                            }
+                            (Some(ResolveVia::Scrape), _) => {
+                                ResolveResult::WwwIpaddressCom(LookupCell::default())
+                            }
+                            (None, Some(Sni::Remain(_))) => {
+                                ResolveResult::CGetAddrInfoOrWwwIpaddressCom(LookupCell::default())
+                            }
+                            (None, _) => ResolveResult::WwwIpaddressCom(LookupCell::default()),
+                        };
+                        (s.to_string(), resolve_result)
+                    })
+                    .chain(snimap.overrided_sni().iter().map(|s| {
                         (
                             s.to_string(),
-                            ResolveResult::WwwIpaddressCom(OnceCell::new()),
+                            ResolveResult::CGetAddrInfo(LookupCell::default()),
                         )
-                    })
-                    .chain(
-                        snimap
-                            .overrided_sni()
-                            .iter()
-                            .map(|s| (s.to_string(), ResolveResult::CGetAddrInfo(OnceCell::new()))),
-                    )
+                    }))
+                    .map(|(host, resolve_result)| (host, RwLock::new(resolve_result)))
                     .collect(),
             ),
+            stats: Default::default(),
+            limit: Default::default(),
+            prefer_ipv6: false,
+            passthrough_unknown: false,
+        }
+    }
+
+    /// `overrides` take precedence over any lookup, hosts not in the map are ignored
+    pub fn with_overrides(mut self, overrides: &HashMap<String, IpAddr>) -> Self {
+        let cache = Arc::get_mut(&mut self.cache)
+            .expect("`with_overrides` should be called before `SniMapResolver` is cloned");
+        for (host, ip_addr) in overrides {
+            if let Some(resolve_result) = cache.get_mut(host) {
+                *resolve_result.get_mut().unwrap() =
+                    ResolveResult::Pinned(LookupCell::with_value(SocketAddr::new(*ip_addr, 443)));
+            }
+        }
+        self
+    }
+
+    /// static entries of the hosts file win over lookups but not over `overrides`,
+    /// hosts not in the map are ignored
+    pub fn with_hosts_file(mut self, static_hosts: &HashMap<String, IpAddr>) -> Self {
+        let cache = Arc::get_mut(&mut self.cache)
+            .expect("`with_hosts_file` should be called before `SniMapResolver` is cloned");
+        for (host, ip_addr) in static_hosts {
+            match cache.get_mut(host).map(|r| r.get_mut().unwrap()) {
+                Some(ResolveResult::Pinned(_)) | None => {}
+                Some(resolve_result) => {
+                    *resolve_result = ResolveResult::HostsFile(LookupCell::with_value(
+                        SocketAddr::new(*ip_addr, 443),
+                    ))
+                }
+            }
         }
+        self
+    }
+
+    /// `false` sends hosts whose sni is kept straight to ipaddress.com
+    pub fn with_remain_system_lookup(mut self, enabled: bool) -> Self {
+        if !enabled {
+            Arc::get_mut(&mut self.cache)
+                .expect("`with_remain_system_lookup` should be called before `SniMapResolver` is cloned")
+                .values_mut()
+                .map(|r| r.get_mut().unwrap())
+                .filter(|r| matches!(r, ResolveResult::CGetAddrInfoOrWwwIpaddressCom(_)))
+                .for_each(|r| *r = ResolveResult::WwwIpaddressCom(LookupCell::default()));
+        }
+        self
+    }
+
+    /// while snimap's block in the hosts file points every mapped hostname at
+    /// loopback, the system resolver has nothing else to say about them
+    pub fn with_hosts_edited(self, edited: bool) -> Self {
+        self.with_remain_system_lookup(!edited)
+    }
+
+    /// at most `max` lookups in flight, the rest wait for a free slot
+    pub fn with_lookup_limit(mut self, max: usize) -> Self {
+        self.limit = Arc::new(LookupLimit::new(max));
+        self
+    }
+
+    /// system lookups pick an ipv6 address when the host has one
+    pub fn with_prefer_ipv6(mut self, prefer_ipv6: bool) -> Self {
+        self.prefer_ipv6 = prefer_ipv6;
+        self
+    }
+
+    /// hosts not in the map go to the system resolver, uncached
+    pub fn with_passthrough_unknown(mut self, passthrough_unknown: bool) -> Self {
+        self.passthrough_unknown = passthrough_unknown;
+        self
     }
 
     pub fn get(&self, host: &str) -> Option<SocketAddr> {
         match self.cache.get(host) {
-            Some(resolve_result) => resolve_result.get_or_init(host),
+            Some(resolve_result) => resolve_result.read().unwrap().get_or_init(
+                host,
+                &self.stats,
+                &self.limit,
+                self.prefer_ipv6,
+            ),
+            None if ip_literal(host).is_some() => ip_literal(host),
+            None if self.passthrough_unknown => ResolveResult::CGetAddrInfo(LookupCell::default())
+                .get_or_init(host, &self.stats, &self.limit, self.prefer_ipv6),
             _ => unreachable!("`SniMapResolver` should only resolve host in `SniMap`"),
         }
     }
+
+    pub fn stats(&self) -> &ResolverStats {
+        &self.stats
+    }
+
+    /// the address `host` resolved to so far, never triggers a lookup
+    pub fn cached(&self, host: &str) -> Option<SocketAddr> {
+        self.cache
+            .get(host)
+            .and_then(|resolve_result| resolve_result.read().unwrap().cached())
+    }
+
+    /// what every mapped host resolves to right now, `None` until its first lookup succeeds
+    pub fn snapshot(&self) -> BTreeMap<&str, Option<SocketAddr>> {
+        self.cache
+            .iter()
+            .map(|(host, resolve_result)| (host.as_str(), resolve_result.read().unwrap().cached()))
+            .collect()
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        self.cache.contains_key(host)
+    }
+
+    /// drop the cached address of `host`, returns whether there was one to drop
+    pub fn invalidate(&self, host: &str) -> bool {
+        self.cache
+            .get(host)
+            .is_some_and(|resolve_result| resolve_result.write().unwrap().reset())
+    }
+
+    /// `invalidate` then look `host` up again, `host` must be in the map
+    pub fn reresolve(&self, host: &str) -> Option<SocketAddr> {
+        if self.invalidate(host) {
+            log::info!(target: RESOLVER, "invalidate {host}");
+        }
+        self.get(host)
+    }
+
+    /// resolve every mapped host ahead of the first request, returns the hosts that failed
+    pub async fn prewarm(&self) -> Vec<String> {
+        let mut hosts = self.cache.keys().cloned().collect::<Vec<_>>();
+        hosts.sort_unstable();
+        log::info!(target: RESOLVER, "prewarming {} hosts", hosts.len());
+        let resolved = join_all(hosts.iter().cloned().map(|host| {
+            let resolver = self.clone();
+            spawn_blocking(move || resolver.get(&host))
+        }))
+        .await;
+        let failed = hosts
+            .into_iter()
+            .zip(resolved)
+            .filter(|(_, resolved)| !matches!(resolved, Ok(Some(_))))
+            .map(|(host, _)| host)
+            .collect::<Vec<_>>();
+        log::info!(
+            target: RESOLVER,
+            "prewarmed {} hosts, {} failed",
+            self.cache.len() - failed.len(),
+            failed.len()
+        );
+        failed
+    }
+
+    pub fn lookup_report(&self, hosts: &[&str]) -> Vec<LookupReport> {
+        hosts
+            .iter()
+            .map(|host| {
+                let resolve_result = self.cache.get(*host).map(|r| r.read().unwrap());
+                LookupReport {
+                    host: host.to_string(),
+                    ip: resolve_result
+                        .as_deref()
+                        .and_then(|r| {
+                            r.get_or_init(host, &self.stats, &self.limit, self.prefer_ipv6)
+                        })
+                        .map(|socket_addr| socket_addr.ip()),
+                    source: resolve_result.as_deref().map(ResolveResult::source),
+                }
+            })
+            .collect()
+    }
 }
 
 impl Clone for SniMapResolver {
     fn clone(&self) -> Self {
         Self {
             cache: self.cache.clone(),
+            stats: self.stats.clone(),
+            limit: self.limit.clone(),
+            prefer_ipv6: self.prefer_ipv6,
+            passthrough_unknown: self.passthrough_unknown,
         }
     }
 }
 
+/// what `ClientPair` needs besides `Resolve`, so tests can point it at a local upstream
+pub trait UpstreamResolver: Resolve {
+    /// the address `host` resolved to so far, never triggers a lookup
+    fn cached(&self, host: &str) -> Option<SocketAddr>;
+
+    /// drop the cached address of `host`, returns whether there was one to drop
+    fn invalidate(&self, host: &str) -> bool;
+}
+
+impl UpstreamResolver for SniMapResolver {
+    fn cached(&self, host: &str) -> Option<SocketAddr> {
+        SniMapResolver::cached(self, host)
+    }
+
+    fn invalidate(&self, host: &str) -> bool {
+        SniMapResolver::invalidate(self, host)
+    }
+}
+
 impl Resolve for SniMapResolver {
     fn lookup<'a>(
         &'a self,
         host: &'a str,
-        _port: u16,
+        port: u16,
     ) -> LocalBoxFuture<'a, Result<Vec<SocketAddr>, Box<dyn std::error::Error>>> {
+        // lookups block on dns or http, keep them off the worker thread
+        let (resolver, owned_host) = (self.clone(), host.to_string());
         Box::pin(async move {
-            Ok(match self.get(host) {
-                Some(socket_addr) => vec![socket_addr],
-                None => vec![],
-            })
+            Ok(
+                match spawn_blocking(move || resolver.get(&owned_host)).await? {
+                    Some(socket_addr) => vec![SocketAddr::new(socket_addr.ip(), port)],
+                    None => vec![],
+                },
+            )
         })
     }
 }
@@ -140,6 +611,20 @@ fn test_ip_lookup_on_ipaddress_com() {
     assert!(!html.is_empty())
 }
 
+#[cfg(test)]
+#[test]
+fn test_ip_lookup_timeout() {
+    use std::{net::TcpListener, time::Instant};
+
+    // accepts connections into the backlog but never answers
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/ip-lookup", listener.local_addr().unwrap());
+
+    let start = Instant::now();
+    assert!(ip_lookup_on(&url, "example.com", Duration::from_millis(300)).is_err());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
 #[cfg(test)]
 #[test]
 fn regex_from_html_get_ip() {
@@ -169,3 +654,440 @@ async fn test_snimap_resolver() {
     assert_ne!(snimap_resolver.get("duckduckgo.com"), None);
     assert!(snimap_resolver.lookup("duckduckgo.com", 443).await.is_ok());
 }
+
+#[cfg(test)]
+#[test]
+fn test_lookup_report_json() {
+    let snimap_resolver = SniMapResolver::from_snimap(&SniMap::new());
+    let reports = snimap_resolver.lookup_report(&["unknown.example"]);
+    assert_eq!(
+        serde_json::to_string(&reports).unwrap(),
+        r#"[{"host":"unknown.example","ip":null,"source":null}]"#
+    );
+    assert_eq!(
+        serde_json::to_string(&LookupReport {
+            host: "example.com".to_string(),
+            ip: Some("93.184.216.34".parse().unwrap()),
+            source: Some("WwwIpaddressCom"),
+        })
+        .unwrap(),
+        r#"{"host":"example.com","ip":"93.184.216.34","source":"WwwIpaddressCom"}"#
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_snimap_resolver_ip_literal() {
+    use crate::config::Mapping;
+
+    let mut snimap: SniMap = Mapping::new("1.2.3.4").into();
+    snimap.merge(Mapping::new("::1"));
+    let snimap_resolver = SniMapResolver::from_snimap(&snimap);
+
+    assert_eq!(
+        snimap_resolver.get("1.2.3.4"),
+        Some("1.2.3.4:443".parse().unwrap())
+    );
+    assert_eq!(
+        snimap_resolver.get("::1"),
+        Some("[::1]:443".parse().unwrap())
+    );
+    assert_eq!(
+        snimap_resolver.get("[::1]"),
+        Some("[::1]:443".parse().unwrap())
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_snimap_resolver_overrides() {
+    use crate::config::Mapping;
+
+    let snimap = Mapping::new("duckduckgo.com")
+        .override_sni("example.com")
+        .into();
+    let overrides = [
+        ("duckduckgo.com".to_string(), "1.2.3.4".parse().unwrap()),
+        ("example.com".to_string(), "::1".parse().unwrap()),
+        ("unused.com".to_string(), "5.6.7.8".parse().unwrap()),
+    ]
+    .into_iter()
+    .collect();
+    let snimap_resolver = SniMapResolver::from_snimap(&snimap).with_overrides(&overrides);
+
+    assert_eq!(
+        snimap_resolver.get("duckduckgo.com"),
+        Some("1.2.3.4:443".parse().unwrap())
+    );
+    assert_eq!(
+        snimap_resolver.get("example.com"),
+        Some("[::1]:443".parse().unwrap())
+    );
+    assert_eq!(
+        snimap_resolver.lookup_report(&["example.com"])[0].source,
+        Some("Pinned")
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_resolver_stats() {
+    use std::sync::atomic::Ordering;
+
+    use crate::config::Mapping;
+
+    let snimap = Mapping::new("example.com").into();
+    let snimap_resolver = SniMapResolver::from_snimap(&snimap).with_overrides(&HashMap::from([(
+        "example.com".to_string(),
+        [1, 2, 3, 4].into(),
+    )]));
+    let cloned = snimap_resolver.clone();
+
+    assert!(snimap_resolver.get("example.com").is_some());
+    assert!(cloned.get("example.com").is_some());
+    assert!(cloned.get("1.2.3.4").is_some());
+
+    let stats = snimap_resolver.stats();
+    assert_eq!(stats.cache_hits.load(Ordering::Relaxed), 2);
+    assert_eq!(stats.lookups.load(Ordering::Relaxed), 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_single_flight() {
+    use std::{sync::atomic::AtomicUsize, thread};
+
+    let socket_addr = LookupCell::default();
+    let stats = ResolverStats::default();
+    let lookups = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| {
+                let result = single_flight(&socket_addr, &stats, &LookupLimit::default(), || {
+                    lookups.fetch_add(1, Ordering::Relaxed);
+                    thread::sleep(Duration::from_millis(100));
+                    Ok(SocketAddr::from(([1, 2, 3, 4], 443)))
+                });
+                assert_eq!(result, Ok(SocketAddr::from(([1, 2, 3, 4], 443))));
+            });
+        }
+    });
+    assert_eq!(lookups.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.lookups.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.cache_hits.load(Ordering::Relaxed), 7);
+}
+
+#[cfg(test)]
+#[test]
+fn test_resolve_source_per_sni() {
+    let snimap = SniMap::builder()
+        .disable("disable.com")
+        .override_sni("override.com", "sni.com")
+        .override_sni("list.com", ["a.sni.com", "b.sni.com"])
+        .remain("remain.com")
+        .build();
+    let source = |resolver: &SniMapResolver, host: &str| {
+        resolver.cache.get(host).map(|r| r.read().unwrap().source())
+    };
+
+    let resolver = SniMapResolver::from_snimap(&snimap);
+    assert_eq!(source(&resolver, "disable.com"), Some("WwwIpaddressCom"));
+    assert_eq!(source(&resolver, "override.com"), Some("WwwIpaddressCom"));
+    assert_eq!(source(&resolver, "list.com"), Some("WwwIpaddressCom"));
+    assert_eq!(
+        source(&resolver, "remain.com"),
+        Some("CGetAddrInfoOrWwwIpaddressCom")
+    );
+    assert_eq!(source(&resolver, "sni.com"), Some("CGetAddrInfo"));
+    assert_eq!(source(&resolver, "b.sni.com"), Some("CGetAddrInfo"));
+
+    let resolver = SniMapResolver::from_snimap(&snimap).with_remain_system_lookup(false);
+    assert_eq!(source(&resolver, "remain.com"), Some("WwwIpaddressCom"));
+    assert_eq!(source(&resolver, "sni.com"), Some("CGetAddrInfo"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_resolve_via() {
+    use crate::config::Config;
+
+    let config: Config = toml::from_str(
+        r#"
+[[groups]]
+name = "system"
+resolve_via = "system"
+
+[[groups.mappings]]
+hostname = "system.com"
+sni = "sni.com"
+
+[[groups.mappings]]
+hostname = "scrape.com"
+resolve_via = "scrape"
+
+[[groups]]
+name = "default"
+
+[[groups.mappings]]
+hostname = "remain.com"
+
+[[groups.mappings]]
+hostname = "scrape.remain.com"
+resolve_via = "scrape"
+"#,
+    )
+    .unwrap();
+    let resolver = SniMapResolver::from_snimap(&config.into());
+    let source = |host: &str| resolver.cache.get(host).map(|r| r.read().unwrap().source());
+    assert_eq!(source("system.com"), Some("System"));
+    assert_eq!(source("sni.com"), Some("CGetAddrInfo"));
+    assert_eq!(source("scrape.com"), Some("WwwIpaddressCom"));
+    assert_eq!(source("remain.com"), Some("CGetAddrInfoOrWwwIpaddressCom"));
+    assert_eq!(source("scrape.remain.com"), Some("WwwIpaddressCom"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_prefer_ipv6() {
+    let (v4, v6) = (
+        "93.184.216.34".parse::<IpAddr>().unwrap(),
+        "2606:2800:220:1::1".parse::<IpAddr>().unwrap(),
+    );
+    assert_eq!(pick_ip_addr(vec![v4, v6], false), Some(v4));
+    assert_eq!(pick_ip_addr(vec![v4, v6], true), Some(v6));
+    assert_eq!(pick_ip_addr(vec![v4], true), Some(v4));
+    assert_eq!(pick_ip_addr(vec![], true), None);
+
+    // `localhost` is dual-stack wherever the system resolver returns both
+    let snimap = SniMap::builder()
+        .override_sni("example.com", "localhost")
+        .build();
+    let resolver = SniMapResolver::from_snimap(&snimap).with_prefer_ipv6(true);
+    let has_ipv6 =
+        lookup_system("localhost").is_ok_and(|ip_addrs| ip_addrs.iter().any(IpAddr::is_ipv6));
+    if has_ipv6 {
+        assert!(resolver.get("localhost").unwrap().is_ipv6());
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_lookup_limit() {
+    use std::{sync::atomic::AtomicUsize, thread};
+
+    let (stats, limit) = (ResolverStats::default(), LookupLimit::new(3));
+    let (in_flight, max_in_flight) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    let cells = (0..12).map(|_| LookupCell::default()).collect::<Vec<_>>();
+    thread::scope(|scope| {
+        for cell in &cells {
+            scope.spawn(|| {
+                single_flight(cell, &stats, &limit, || {
+                    let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(n, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    Ok(SocketAddr::from(([127, 0, 0, 1], 443)))
+                })
+                .unwrap();
+            });
+        }
+    });
+    assert_eq!(stats.lookups.load(Ordering::Relaxed), 12);
+    assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+}
+
+#[cfg(test)]
+#[test]
+fn test_remain_lookup_with_hosts_edited() {
+    use crate::utils::static_hosts;
+
+    let hosts = "127.0.0.1\tlocalhost
+# Auto Generate by snimap
+127.0.0.1\tremain.com
+127.0.0.1\tpixiv.net
+# Auto Generate by snimap
+";
+    let snimap = SniMap::builder()
+        .remain("remain.com")
+        .override_sni("pixiv.net", "www.fanbox.cc")
+        .build();
+    let resolver = |edited| {
+        SniMapResolver::from_snimap(&snimap)
+            .with_hosts_file(&static_hosts(hosts))
+            .with_hosts_edited(edited)
+    };
+    let source =
+        |resolver: &SniMapResolver, host: &str| resolver.cache[host].read().unwrap().source();
+
+    // getaddrinfo would only find the block's loopback, so it is not asked
+    let edited = resolver(true);
+    assert_eq!(source(&edited, "remain.com"), "WwwIpaddressCom");
+    assert_eq!(source(&edited, "www.fanbox.cc"), "CGetAddrInfo");
+
+    let untouched = resolver(false);
+    assert_eq!(
+        source(&untouched, "remain.com"),
+        "CGetAddrInfoOrWwwIpaddressCom"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_resolve_via_system_skips_loopback() {
+    use crate::config::Config;
+
+    // what the hosts file answers for a mapped hostname while snimap runs
+    let config: Config = toml::from_str(
+        r#"
+[[groups]]
+name = "system"
+resolve_via = "system"
+
+[[groups.mappings]]
+hostname = "localhost"
+"#,
+    )
+    .unwrap();
+    let resolver = SniMapResolver::from_snimap(&config.into());
+    assert_eq!(resolver.get("localhost"), None);
+    assert_eq!(resolver.stats().lookups.load(Ordering::Relaxed), 1);
+}
+
+#[cfg(test)]
+#[actix_web::test]
+async fn test_prewarm() {
+    let snimap = SniMap::builder()
+        .override_sni("example.com", "localhost")
+        .build();
+    let resolver = SniMapResolver::from_snimap(&snimap).with_overrides(&HashMap::from([(
+        "example.com".to_string(),
+        "1.2.3.4".parse().unwrap(),
+    )]));
+    let cached = |host: &str| resolver.cache[host].read().unwrap().cached();
+    assert_eq!(cached("localhost"), None);
+
+    assert!(resolver.prewarm().await.is_empty());
+    assert!(cached("localhost").is_some_and(|socket_addr| socket_addr.ip().is_loopback()));
+    assert_eq!(
+        cached("example.com"),
+        Some(SocketAddr::from(([1, 2, 3, 4], 443)))
+    );
+    assert_eq!(resolver.stats().lookups.load(Ordering::Relaxed), 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_snapshot() {
+    let snimap = SniMap::builder()
+        .override_sni("example.com", "example.net")
+        .build();
+    let resolver = SniMapResolver::from_snimap(&snimap).with_overrides(&HashMap::from([(
+        "example.com".to_string(),
+        "1.2.3.4".parse().unwrap(),
+    )]));
+    assert_eq!(
+        resolver.snapshot(),
+        BTreeMap::from([
+            ("example.com", Some(SocketAddr::from(([1, 2, 3, 4], 443)))),
+            ("example.net", None),
+        ])
+    );
+    assert_eq!(resolver.stats().lookups.load(Ordering::Relaxed), 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_invalidate() {
+    let snimap = SniMap::builder()
+        .override_sni("example.com", "localhost")
+        .build();
+    let resolver = SniMapResolver::from_snimap(&snimap).with_overrides(&HashMap::from([(
+        "example.com".to_string(),
+        "1.2.3.4".parse().unwrap(),
+    )]));
+    // a stale address left by an earlier lookup
+    *resolver.cache["localhost"].write().unwrap() = ResolveResult::CGetAddrInfo(
+        LookupCell::with_value(SocketAddr::from(([1, 2, 3, 4], 443))),
+    );
+    assert_eq!(
+        resolver.get("localhost"),
+        Some(SocketAddr::from(([1, 2, 3, 4], 443)))
+    );
+
+    assert!(resolver.invalidate("localhost"));
+    assert_eq!(resolver.snapshot()["localhost"], None);
+    assert!(resolver
+        .get("localhost")
+        .is_some_and(|socket_addr| socket_addr.ip().is_loopback()));
+    assert_eq!(resolver.stats().lookups.load(Ordering::Relaxed), 1);
+
+    // pinned addresses survive, unknown hosts are ignored
+    assert!(!resolver.invalidate("example.com"));
+    assert_eq!(
+        resolver.reresolve("example.com"),
+        Some(SocketAddr::from(([1, 2, 3, 4], 443)))
+    );
+    assert!(!resolver.invalidate("unknown.com"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_negative_cache() {
+    use std::sync::atomic::AtomicUsize;
+
+    let (stats, limit) = (ResolverStats::default(), LookupLimit::default());
+    let cell = LookupCell::default();
+    let scrapes = AtomicUsize::new(0);
+    let scrape = || {
+        scrapes.fetch_add(1, Ordering::Relaxed);
+        Err("no match is found".to_string())
+    };
+
+    assert!(single_flight(&cell, &stats, &limit, scrape).is_err());
+    assert!(single_flight(&cell, &stats, &limit, scrape).is_err());
+    assert_eq!(scrapes.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.lookups.load(Ordering::Relaxed), 1);
+
+    // retried once the failure is old enough
+    if let Some(failed_at) = Instant::now().checked_sub(NEGATIVE_TTL) {
+        cell.failure.lock().unwrap().as_mut().unwrap().0 = failed_at;
+        assert!(single_flight(&cell, &stats, &limit, scrape).is_err());
+        assert_eq!(scrapes.load(Ordering::Relaxed), 2);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_hosts_file() {
+    use crate::utils::static_hosts;
+
+    let hosts = "127.0.0.1\tlocalhost
+93.184.216.34\texample.com
+5.6.7.8\texample.net
+# Auto Generate by snimap
+127.0.0.1\tpixiv.net
+# Auto Generate by snimap
+";
+    let snimap = SniMap::builder()
+        .override_sni("pixiv.net", "www.fanbox.cc")
+        .remain("example.com")
+        .remain("example.net")
+        .build();
+    let resolver = SniMapResolver::from_snimap(&snimap)
+        .with_overrides(&HashMap::from([(
+            "example.net".to_string(),
+            "1.2.3.4".parse().unwrap(),
+        )]))
+        .with_hosts_file(&static_hosts(hosts));
+    let source = |host: &str| resolver.cache[host].read().unwrap().source();
+
+    assert_eq!(source("example.com"), "HostsFile");
+    assert_eq!(
+        resolver.get("example.com"),
+        Some(SocketAddr::from(([93, 184, 216, 34], 443)))
+    );
+    assert_eq!(source("example.net"), "Pinned");
+    assert_eq!(source("pixiv.net"), "WwwIpaddressCom");
+    assert!(!resolver.invalidate("example.com"));
+    assert_eq!(resolver.stats().lookups.load(Ordering::Relaxed), 0);
+}
@@ -1,26 +1,232 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
 
 use rcgen::{
-    Certificate as RcgenCert, CertificateParams, DistinguishedName, DnType, KeyPair, RcgenError,
-    SanType,
+    BasicConstraints, Certificate as RcgenCert, CertificateParams, DistinguishedName, DnType, IsCa,
+    KeyPair, KeyUsagePurpose, SanType, PKCS_ECDSA_P256_SHA256,
 };
 use rustls::{
+    client::{
+        ClientSessionMemoryCache, HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+        WebPkiVerifier,
+    },
+    internal::msgs::handshake::DigitallySignedStruct,
+    version::TLS13,
     Certificate as RustlsCert, ClientConfig as RustlsClientConfig, Error, OwnedTrustAnchor,
-    PrivateKey, RootCertStore, ServerConfig as RustlsServerConfig,
+    PrivateKey, RootCertStore, ServerConfig as RustlsServerConfig, ServerName, SignatureScheme,
+    SupportedProtocolVersion, ALL_VERSIONS, DEFAULT_VERSIONS,
+};
+use x509_parser::{extensions::GeneralName, parse_x509_certificate, pem::parse_x509_pem};
+
+use crate::{
+    dirs::config_dir,
+    error::{SnimapError, SnimapResult},
+    logging::{FORWARD, PROXY},
+    utils::{create_dir_all, write},
 };
 
+/// CA certificate and key under the config directory
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "cakey.pem";
+
+/// PEM of the CA key, takes precedence over `CA_KEY_FILE_ENV` and the stored key
+pub const CA_KEY_ENV: &str = "SNIMAP_CA_KEY";
+
+/// path of a PEM file holding the CA key
+pub const CA_KEY_FILE_ENV: &str = "SNIMAP_CA_KEY_FILE";
+
+/// tls sessions remembered per client config, one per upstream hostname
+pub const SESSION_CACHE_SIZE: usize = 256;
+
 /// DER-encoded
 pub struct SingleCert {
     pub cert: Vec<u8>,
     pub key: Vec<u8>,
+    pub alt_dnsnames: HashSet<String>,
+}
+
+/// difference between a cert's subject alt names and the configured hostnames
+#[derive(Debug, PartialEq, Eq)]
+pub struct SanDiff {
+    /// configured but not covered by the cert
+    pub missing: Vec<String>,
+    /// covered by the cert but no longer configured
+    pub stale: Vec<String>,
+}
+
+impl SanDiff {
+    pub fn new(alt_dnsnames: &HashSet<String>, hostnames: &HashSet<&str>) -> Self {
+        let mut missing: Vec<String> = hostnames
+            .iter()
+            .filter(|h| !alt_dnsnames.contains(**h))
+            .map(|h| h.to_string())
+            .collect();
+        let mut stale: Vec<String> = alt_dnsnames
+            .iter()
+            .filter(|s| !hostnames.contains(s.as_str()))
+            .cloned()
+            .collect();
+        missing.sort();
+        stale.sort();
+        Self { missing, stale }
+    }
+}
+
+/// the CA signing every leaf cert, created on first run so no two installs share one
+pub struct Ca {
+    pem: String,
+    cert: RcgenCert,
+}
+
+impl Ca {
+    /// a fresh CA kept in memory only
+    pub fn new() -> SnimapResult<Self> {
+        Self::generate(KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?)
+    }
+
+    /// the CA in the config directory, see `load_or_create`
+    pub fn from_default_dir() -> SnimapResult<Self> {
+        Self::load_or_create(&config_dir())
+    }
+
+    /// the CA stored in `dir`, creating what is missing of it; the key comes from
+    /// `SNIMAP_CA_KEY` or `SNIMAP_CA_KEY_FILE` when either is set
+    pub fn load_or_create(dir: &Path) -> SnimapResult<Self> {
+        Self::load_or_create_with(
+            dir,
+            env::var(CA_KEY_ENV).ok(),
+            env::var_os(CA_KEY_FILE_ENV).as_deref().map(Path::new),
+        )
+    }
+
+    /// `load_or_create` with the two environment variables passed in
+    fn load_or_create_with(
+        dir: &Path,
+        env_pem: Option<String>,
+        env_path: Option<&Path>,
+    ) -> SnimapResult<Self> {
+        let (cert_path, key_path) = (dir.join(CA_CERT_FILE), dir.join(CA_KEY_FILE));
+        let key_pem = ca_key_pem(env_pem, env_path, &key_path)?;
+        let cert_pem = read_if_exists(&cert_path)?;
+        match (key_pem, cert_pem) {
+            (Some(key_pem), Some(cert_pem)) => {
+                Self::from_pem(cert_pem, KeyPair::from_pem(&key_pem)?)
+            }
+            (Some(key_pem), None) => {
+                let ca = Self::generate(KeyPair::from_pem(&key_pem)?)?;
+                create_dir_all(&dir.to_path_buf()).map_err(|e| SnimapError::Cert(e.into()))?;
+                write(&cert_path, &ca.pem).map_err(|e| SnimapError::Cert(e.into()))?;
+                log::info!(target: PROXY, "created CA certificate {:?}", cert_path);
+                Ok(ca)
+            }
+            (None, Some(_)) => Err(SnimapError::Cert(
+                format!(
+                    "{:?} has no key, set {CA_KEY_ENV} or remove it to create a new CA",
+                    cert_path
+                )
+                .into(),
+            )),
+            (None, None) => {
+                let ca = Self::new()?;
+                create_dir_all(&dir.to_path_buf()).map_err(|e| SnimapError::Cert(e.into()))?;
+                write_private(&key_path, &ca.cert.serialize_private_key_pem())?;
+                write(&cert_path, &ca.pem).map_err(|e| SnimapError::Cert(e.into()))?;
+                log::info!(target: PROXY, "created CA {:?}, install it before use", cert_path);
+                Ok(ca)
+            }
+        }
+    }
+
+    fn generate(key_pair: KeyPair) -> SnimapResult<Self> {
+        let mut params = CertificateParams::default();
+        params.alg = key_pair
+            .compatible_algs()
+            .next()
+            .ok_or_else(|| SnimapError::Cert("unsupported CA key algorithm".into()))?;
+        params.distinguished_name = {
+            let mut dn = DistinguishedName::new();
+            dn.push(DnType::CommonName, "snimap_root_ca");
+            dn
+        };
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        params.key_pair = Some(key_pair);
+        let cert = RcgenCert::from_params(params)?;
+        Ok(Self {
+            pem: cert.serialize_pem()?,
+            cert,
+        })
+    }
+
+    fn from_pem(pem: String, key_pair: KeyPair) -> SnimapResult<Self> {
+        let (_, x509_pem) =
+            parse_x509_pem(pem.as_bytes()).map_err(|e| SnimapError::Cert(e.into()))?;
+        let x509 = x509_pem
+            .parse_x509()
+            .map_err(|e| SnimapError::Cert(e.into()))?;
+        if x509.public_key().subject_public_key.data != key_pair.public_key_raw() {
+            return Err(SnimapError::Cert(
+                "the CA key does not belong to the CA certificate".into(),
+            ));
+        }
+        let cert = RcgenCert::from_params(CertificateParams::from_ca_cert_pem(&pem, key_pair)?)?;
+        Ok(Self { pem, cert })
+    }
+
+    /// PEM of the certificate, for installing into a trust store
+    pub fn pem(&self) -> &str {
+        &self.pem
+    }
+
+    /// DER of the certificate, for a `RootCertStore`
+    pub fn der(&self) -> Vec<u8> {
+        let (_, pem) = parse_x509_pem(self.pem.as_bytes()).expect("`Ca` holds valid PEM");
+        pem.contents
+    }
+}
+
+/// the key given in the environment, otherwise the stored one
+fn ca_key_pem(
+    pem: Option<String>,
+    path: Option<&Path>,
+    stored: &Path,
+) -> SnimapResult<Option<String>> {
+    match (pem, path) {
+        (Some(pem), _) => Ok(Some(pem)),
+        (None, Some(path)) => fs::read_to_string(path).map(Some).map_err(|e| {
+            SnimapError::Cert(format!("failed to read {CA_KEY_FILE_ENV} {path:?}: {e}").into())
+        }),
+        (None, None) => read_if_exists(stored),
+    }
+}
+
+fn read_if_exists(path: &Path) -> SnimapResult<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(SnimapError::Cert(e.into())),
+    }
 }
 
-pub async fn cert_generate(alt_dnsname: &HashSet<&str>) -> Result<SingleCert, RcgenError> {
-    let ca = RcgenCert::from_params(CertificateParams::from_ca_cert_pem(
-        include_str!("../private/ca.pem"),
-        KeyPair::from_pem(include_str!("../private/cakey.pem"))?,
-    )?)?;
+/// like `utils::write`, without logging the contents and readable by the owner only
+fn write_private(path: &Path, contents: &str) -> SnimapResult<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| SnimapError::Cert(format!("failed to write {path:?}: {e}").into()))
+}
 
+pub async fn cert_generate(alt_dnsname: &HashSet<&str>, ca: &Ca) -> SnimapResult<SingleCert> {
     let mut cert_params = CertificateParams::default();
     cert_params.distinguished_name = {
         let mut dn = DistinguishedName::new();
@@ -35,11 +241,17 @@ pub async fn cert_generate(alt_dnsname: &HashSet<&str>) -> Result<SingleCert, Rc
     let server_cert = RcgenCert::from_params(cert_params)?;
 
     Ok(SingleCert {
-        cert: server_cert.serialize_der_with_signer(&ca)?,
+        cert: server_cert.serialize_der_with_signer(&ca.cert)?,
         key: server_cert.serialize_private_key_der(),
+        alt_dnsnames: alt_dnsname.iter().map(|s| s.to_string()).collect(),
     })
 }
 
+/// write the CA certificate (PEM) for installing into a trust store
+pub fn export_ca(path: &PathBuf, ca: &Ca) -> SnimapResult<()> {
+    write(path, ca.pem()).map_err(|e| SnimapError::Cert(e.into()))
+}
+
 pub trait DisableSni {
     fn disable_sni(self) -> Self;
 }
@@ -51,7 +263,201 @@ impl DisableSni for RustlsClientConfig {
     }
 }
 
+pub trait WithAlpn {
+    fn with_alpn<P: AsRef<[u8]>>(self, protocols: &[P]) -> Self;
+}
+
+impl WithAlpn for RustlsClientConfig {
+    fn with_alpn<P: AsRef<[u8]>>(mut self, protocols: &[P]) -> Self {
+        self.alpn_protocols = protocols.iter().map(|p| p.as_ref().to_vec()).collect();
+        self
+    }
+}
+
+pub trait EnableH2: WithAlpn + Sized {
+    fn enable_h2(self) -> Self {
+        self.with_alpn(&["h2", "http/1.1"])
+    }
+}
+
+impl EnableH2 for RustlsClientConfig {}
+
+/// resume tls sessions with upstreams instead of a full handshake on every new connection
+pub trait WithSessionCache {
+    fn with_session_cache(self, size: usize) -> Self;
+}
+
+impl WithSessionCache for RustlsClientConfig {
+    /// a fresh cache, so sessions are not shared with configs this one was cloned from
+    fn with_session_cache(mut self, size: usize) -> Self {
+        self.enable_tickets = true;
+        self.session_storage = ClientSessionMemoryCache::new(size);
+        self
+    }
+}
+
+/// subject and dns names of a certificate presented by an upstream
+#[derive(Debug, PartialEq, Eq)]
+pub struct PeerCert {
+    pub subject: String,
+    pub dns_names: Vec<String>,
+}
+
+impl PeerCert {
+    pub fn parse(cert: &RustlsCert) -> Option<Self> {
+        let (_, x509) = parse_x509_certificate(&cert.0).ok()?;
+        let dns_names = match x509.subject_alternative_name() {
+            Ok(Some(san)) => san
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(dns_name) => Some(dns_name.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+        Some(Self {
+            subject: x509.subject().to_string(),
+            dns_names,
+        })
+    }
+}
+
+/// how upstream certificates are verified
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VerifyOptions {
+    /// log the certificate every upstream presents
+    pub log_peer_cert: bool,
+    /// ask for signed certificate timestamps, they are not checked either way
+    pub request_scts: bool,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            log_peer_cert: false,
+            request_scts: true,
+        }
+    }
+}
+
+/// applies `1` on top of `0`, which decides whether a cert is accepted
+struct UpstreamVerifier(Arc<dyn ServerCertVerifier>, VerifyOptions);
+
+impl ServerCertVerifier for UpstreamVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &RustlsCert,
+        intermediates: &[RustlsCert],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if !self.1.log_peer_cert {
+            return self.0.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            );
+        }
+        match PeerCert::parse(end_entity) {
+            Some(PeerCert { subject, dns_names }) => log::info!(
+                target: FORWARD,
+                "{server_name:?} presented subject: {subject:?} dns names: {dns_names:?}"
+            ),
+            None => log::warn!(target: FORWARD, "{server_name:?} presented an unparsable cert"),
+        }
+        self.0
+            .verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )
+            .inspect_err(|e| log::warn!(target: FORWARD, "{server_name:?} cert rejected: {e}"))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &RustlsCert,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &RustlsCert,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+
+    fn request_scts(&self) -> bool {
+        self.1.request_scts && self.0.request_scts()
+    }
+}
+
+/// log the certificate every upstream presents, for `--debug-tls`
+pub trait DebugTls {
+    fn debug_tls(self) -> Self;
+}
+
+impl DebugTls for RustlsClientConfig {
+    fn debug_tls(self) -> Self {
+        self.with_verify_options(VerifyOptions {
+            log_peer_cert: true,
+            ..Default::default()
+        })
+    }
+}
+
+/// replace the default verifier, which is what `VerifyOptions::default()` gives
+pub trait WithVerifyOptions {
+    fn with_verify_options(self, options: VerifyOptions) -> Self;
+}
+
+impl WithVerifyOptions for RustlsClientConfig {
+    fn with_verify_options(mut self, options: VerifyOptions) -> Self {
+        let verifier = WebPkiVerifier::new(root_cert_store(), None);
+        self.dangerous()
+            .set_certificate_verifier(Arc::new(UpstreamVerifier(Arc::new(verifier), options)));
+        self
+    }
+}
+
+pub type TlsVersions = &'static [&'static SupportedProtocolVersion];
+
+static TLS13_ONLY: TlsVersions = &[&TLS13];
+
+/// protocol versions from `min_version` ("1.2" or "1.3") upwards
+pub fn tls_versions(min_version: &str) -> Option<TlsVersions> {
+    match min_version {
+        "1.2" => Some(ALL_VERSIONS),
+        "1.3" => Some(TLS13_ONLY),
+        _ => None,
+    }
+}
+
 pub fn rustls_client_config() -> RustlsClientConfig {
+    rustls_client_config_with_versions(DEFAULT_VERSIONS)
+}
+
+fn root_cert_store() -> RootCertStore {
     let mut root_store = RootCertStore::empty();
 
     root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
@@ -62,18 +468,342 @@ pub fn rustls_client_config() -> RustlsClientConfig {
         )
     }));
 
+    root_store
+}
+
+pub fn rustls_client_config_with_versions(versions: TlsVersions) -> RustlsClientConfig {
+    let root_store = root_cert_store();
+
     RustlsClientConfig::builder()
-        .with_safe_defaults()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .expect("safe default cipher suites support every tls version")
         .with_root_certificates(root_store)
         .with_no_client_auth()
 }
 
-pub fn rustls_server_config(single_cert: SingleCert) -> Result<RustlsServerConfig, Error> {
+pub fn rustls_server_config(
+    single_cert: SingleCert,
+    versions: TlsVersions,
+) -> Result<RustlsServerConfig, Error> {
     RustlsServerConfig::builder()
-        .with_safe_defaults()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)?
         .with_no_client_auth()
         .with_single_cert(
             vec![RustlsCert(single_cert.cert)],
             PrivateKey(single_cert.key),
         )
 }
+
+#[cfg(test)]
+#[test]
+fn test_rustls_client_config_alpn() {
+    assert!(rustls_client_config().alpn_protocols.is_empty());
+    assert_eq!(
+        rustls_client_config()
+            .disable_sni()
+            .with_alpn(&["h2", "http/1.1"])
+            .alpn_protocols,
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    );
+    assert_eq!(
+        rustls_client_config().enable_h2().alpn_protocols,
+        rustls_client_config()
+            .with_alpn(&[b"h2".as_ref(), b"http/1.1".as_ref()])
+            .alpn_protocols
+    );
+}
+
+#[cfg(test)]
+#[actix_web::test]
+async fn test_tls_versions() {
+    use rustls::ProtocolVersion;
+
+    assert!(tls_versions("1.1").is_none());
+
+    let client_config = rustls_client_config_with_versions(tls_versions("1.3").unwrap());
+    assert!(client_config.supports_version(ProtocolVersion::TLSv1_3));
+    assert!(!client_config.supports_version(ProtocolVersion::TLSv1_2));
+
+    let server_config = rustls_server_config(
+        cert_generate(&["localhost"].into_iter().collect(), &Ca::new().unwrap())
+            .await
+            .unwrap(),
+        tls_versions("1.2").unwrap(),
+    )
+    .unwrap();
+    assert!(server_config.supports_version(ProtocolVersion::TLSv1_3));
+    assert!(server_config.supports_version(ProtocolVersion::TLSv1_2));
+}
+
+#[cfg(test)]
+#[test]
+fn test_export_ca() {
+    let ca = Ca::new().unwrap();
+    let path = std::env::temp_dir().join("snimap_test_export_ca.pem");
+    export_ca(&path, &ca).unwrap();
+    let pem = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(pem, ca.pem());
+    let (_, pem) = parse_x509_pem(pem.as_bytes()).unwrap();
+    assert!(pem.parse_x509().unwrap().is_ca());
+}
+
+#[cfg(test)]
+#[test]
+fn test_ca_load_or_create() {
+    let dir = std::env::temp_dir().join("snimap_test_ca_load_or_create");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    // first run
+    let ca = Ca::load_or_create_with(&dir, None, None).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dir.join(CA_CERT_FILE)).unwrap(),
+        ca.pem()
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(dir.join(CA_KEY_FILE))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // later runs reuse it, and so do the certs it signs
+    let again = Ca::load_or_create_with(&dir, None, None).unwrap();
+    assert_eq!(again.pem(), ca.pem());
+    assert_ne!(Ca::new().unwrap().pem(), ca.pem());
+
+    // a cert without its key is refused rather than silently replaced
+    let key_pem = std::fs::read_to_string(dir.join(CA_KEY_FILE)).unwrap();
+    std::fs::remove_file(dir.join(CA_KEY_FILE)).unwrap();
+    assert!(Ca::load_or_create_with(&dir, None, None).is_err());
+
+    // unless the key comes from the environment
+    let from_env = Ca::load_or_create_with(&dir, Some(key_pem), None).unwrap();
+    assert_eq!(from_env.pem(), ca.pem());
+    assert!(!dir.join(CA_KEY_FILE).exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_ca_key_pem() {
+    let stored = std::env::temp_dir().join("snimap_test_ca_key_stored.pem");
+    let path = std::env::temp_dir().join("snimap_test_ca_key.pem");
+    assert_eq!(
+        ca_key_pem(
+            Some("from env".to_string()),
+            Some(Path::new("/nonexistent")),
+            &stored
+        )
+        .unwrap(),
+        Some("from env".to_string())
+    );
+
+    std::fs::write(&path, "from file").unwrap();
+    let pem = ca_key_pem(None, Some(&path), &stored);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(pem.unwrap(), Some("from file".to_string()));
+    assert!(ca_key_pem(None, Some(&path), &stored).is_err());
+
+    assert_eq!(ca_key_pem(None, None, &stored).unwrap(), None);
+    std::fs::write(&stored, "stored").unwrap();
+    let pem = ca_key_pem(None, None, &stored);
+    std::fs::remove_file(&stored).unwrap();
+    assert_eq!(pem.unwrap(), Some("stored".to_string()));
+
+    // a key from the environment gets a matching cert
+    let key_pair = KeyPair::generate(&PKCS_ECDSA_P256_SHA256).unwrap();
+    let ca = Ca::generate(KeyPair::from_pem(&key_pair.serialize_pem()).unwrap()).unwrap();
+    assert!(Ca::from_pem(ca.pem().to_string(), key_pair).is_ok());
+    assert!(Ca::from_pem(
+        ca.pem().to_string(),
+        KeyPair::generate(&PKCS_ECDSA_P256_SHA256).unwrap()
+    )
+    .is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_san_diff() {
+    let alt_dnsnames = ["a.com", "b.com"].iter().map(|s| s.to_string()).collect();
+
+    let diff = SanDiff::new(&alt_dnsnames, &["a.com", "b.com"].into_iter().collect());
+    assert!(diff.missing.is_empty() && diff.stale.is_empty());
+
+    let diff = SanDiff::new(
+        &alt_dnsnames,
+        &["b.com", "d.com", "c.com"].into_iter().collect(),
+    );
+    assert_eq!(
+        diff,
+        SanDiff {
+            missing: vec!["c.com".to_string(), "d.com".to_string()],
+            stale: vec!["a.com".to_string()],
+        }
+    );
+}
+
+#[cfg(test)]
+#[actix_web::test]
+async fn test_peer_cert() {
+    let cert = RustlsCert(
+        cert_generate(
+            &["a.com", "b.com"].into_iter().collect(),
+            &Ca::new().unwrap(),
+        )
+        .await
+        .unwrap()
+        .cert,
+    );
+    let mut peer_cert = PeerCert::parse(&cert).unwrap();
+    peer_cert.dns_names.sort();
+    assert_eq!(
+        peer_cert,
+        PeerCert {
+            subject: "CN=snimap".to_string(),
+            dns_names: vec!["a.com".to_string(), "b.com".to_string()],
+        }
+    );
+    assert_eq!(PeerCert::parse(&RustlsCert(b"not a cert".to_vec())), None);
+
+    // signed by the snimap ca, which is not a public root
+    let verifier = UpstreamVerifier(
+        Arc::new(WebPkiVerifier::new(root_cert_store(), None)),
+        VerifyOptions {
+            log_peer_cert: true,
+            ..Default::default()
+        },
+    );
+    assert!(verifier
+        .verify_server_cert(
+            &cert,
+            &[],
+            &ServerName::try_from("a.com").unwrap(),
+            &mut std::iter::empty(),
+            &[],
+            SystemTime::now(),
+        )
+        .is_err());
+    assert_eq!(
+        rustls_client_config().debug_tls().alpn_protocols,
+        rustls_client_config().alpn_protocols
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_request_scts() {
+    let webpki = || Arc::new(WebPkiVerifier::new(root_cert_store(), None));
+    assert!(UpstreamVerifier(webpki(), VerifyOptions::default()).request_scts());
+    for log_peer_cert in [false, true] {
+        let options = VerifyOptions {
+            log_peer_cert,
+            request_scts: false,
+        };
+        assert!(!UpstreamVerifier(webpki(), options).request_scts());
+    }
+
+    for request_scts in [true, false] {
+        let client_config = rustls_client_config()
+            .enable_h2()
+            .with_verify_options(VerifyOptions {
+                request_scts,
+                ..Default::default()
+            });
+        assert!(client_config.enable_sni);
+        assert_eq!(
+            client_config.alpn_protocols,
+            rustls_client_config().enable_h2().alpn_protocols
+        );
+    }
+}
+
+#[cfg(test)]
+#[actix_web::test]
+async fn test_session_resumption() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rustls::{ClientConnection, Connection, ServerConnection};
+
+    /// accepts any certificate and counts how often one was presented,
+    /// which only happens on a full handshake
+    #[derive(Default)]
+    struct CountFullHandshakes(AtomicUsize);
+
+    impl ServerCertVerifier for CountFullHandshakes {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &RustlsCert,
+            _intermediates: &[RustlsCert],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<ServerCertVerified, Error> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    fn transfer(from: &mut Connection, to: &mut Connection) {
+        let mut buf = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut buf).unwrap();
+        }
+        if !buf.is_empty() {
+            to.read_tls(&mut buf.as_slice()).unwrap();
+            to.process_new_packets().unwrap();
+        }
+    }
+
+    fn handshake(client_config: &Arc<RustlsClientConfig>, server_config: &Arc<RustlsServerConfig>) {
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut client =
+            Connection::Client(ClientConnection::new(client_config.clone(), server_name).unwrap());
+        let mut server = Connection::Server(ServerConnection::new(server_config.clone()).unwrap());
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server);
+            transfer(&mut server, &mut client);
+        }
+        // tls 1.3 tickets arrive after the handshake
+        transfer(&mut server, &mut client);
+    }
+
+    let server_config = Arc::new(
+        rustls_server_config(
+            cert_generate(&["localhost"].into_iter().collect(), &Ca::new().unwrap())
+                .await
+                .unwrap(),
+            DEFAULT_VERSIONS,
+        )
+        .unwrap(),
+    );
+    let full_handshakes = Arc::new(CountFullHandshakes::default());
+    let client_config = |size| {
+        let mut client_config = rustls_client_config().with_session_cache(size);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(full_handshakes.clone());
+        Arc::new(client_config)
+    };
+
+    let (enable_sni, disable_sni) = (
+        client_config(SESSION_CACHE_SIZE),
+        Arc::new(RustlsClientConfig::clone(&client_config(SESSION_CACHE_SIZE)).disable_sni()),
+    );
+    for _ in 0..3 {
+        handshake(&enable_sni, &server_config);
+    }
+    assert_eq!(full_handshakes.0.load(Ordering::Relaxed), 1);
+    // a separate cache, nothing to resume yet
+    handshake(&disable_sni, &server_config);
+    handshake(&disable_sni, &server_config);
+    assert_eq!(full_handshakes.0.load(Ordering::Relaxed), 2);
+}
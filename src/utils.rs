@@ -1,6 +1,17 @@
-use std::{collections::HashSet, fs, io::Error, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Error,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
 
-use crate::{anyway::AnyResult, dirs::hosts_path, ok};
+use crate::{
+    dirs::hosts_path,
+    error::{SnimapError, SnimapResult},
+    logging::PROXY,
+    ok,
+};
 
 pub fn read_to_string(path: &PathBuf) -> Result<String, Error> {
     log::debug!("read_to_string {:?}", path);
@@ -17,41 +28,140 @@ pub fn create_dir_all(path: &PathBuf) -> Result<(), Error> {
     fs::create_dir_all(path)
 }
 
-pub async fn edit_hosts(hostnames: &HashSet<&str>) -> AnyResult<()> {
-    let hosts_path = hosts_path().ok_or("hosts file not found")?;
+const HOSTS_FLAG: &str = "# Auto Generate by snimap";
 
-    let mut hosts_string = read_to_string(&hosts_path)?;
+pub async fn edit_hosts(hostnames: &HashMap<&str, Option<&str>>) -> SnimapResult<()> {
+    let hosts_path =
+        hosts_path().ok_or_else(|| SnimapError::Hosts("hosts file not found".into()))?;
+
+    edit_hosts_at(&hosts_path, hostnames)
+}
+
+fn edit_hosts_at(
+    hosts_path: &PathBuf,
+    hostnames: &HashMap<&str, Option<&str>>,
+) -> SnimapResult<()> {
+    let mut hosts_string = read_to_string(hosts_path).map_err(|e| SnimapError::Hosts(e.into()))?;
 
     hosts_string = gen_hosts(&hosts_string, hostnames);
 
-    write(&hosts_path, &hosts_string)?;
+    write(hosts_path, &hosts_string).map_err(|e| SnimapError::Hosts(e.into()))?;
 
     ok!()
 }
 
-fn gen_hosts(old_hosts: &str, hostnames: &HashSet<&str>) -> String {
-    let mut is_will_change = false;
-    let flag = "# Auto Generate by snimap";
+/// what to run when snimap could not remove its block from the hosts file
+pub fn restore_hosts_hint() -> String {
+    let hosts_path = hosts_path().unwrap_or_else(|| PathBuf::from("/etc/hosts"));
+    restore_hosts_hint_for(&hosts_path, std::env::consts::OS)
+}
+
+fn restore_hosts_hint_for(hosts_path: &Path, os: &str) -> String {
+    match os {
+        "windows" => format!(
+            "open {} as administrator and delete the lines between the two `{HOSTS_FLAG}` lines",
+            hosts_path.display()
+        ),
+        // bsd sed takes the backup suffix as a separate, here empty, argument
+        "macos" => format!(
+            "sudo sed -i '' '/^{HOSTS_FLAG}$/,/^{HOSTS_FLAG}$/d' {}",
+            hosts_path.display()
+        ),
+        _ => format!(
+            "sudo sed -i '/^{HOSTS_FLAG}$/,/^{HOSTS_FLAG}$/d' {}",
+            hosts_path.display()
+        ),
+    }
+}
+
+/// `edit_hosts` unless hosts editing is turned off by `--no-hosts`,
+/// an empty `hostnames` restores the hosts file on shutdown
+pub async fn sync_hosts(
+    hostnames: &HashMap<&str, Option<&str>>,
+    no_hosts: bool,
+) -> SnimapResult<()> {
+    sync_hosts_at(hosts_path(), hostnames, no_hosts)
+}
+
+fn sync_hosts_at(
+    hosts_path: Option<PathBuf>,
+    hostnames: &HashMap<&str, Option<&str>>,
+    no_hosts: bool,
+) -> SnimapResult<()> {
+    if no_hosts {
+        log::info!(target: PROXY, "skip editing hosts");
+        return ok!();
+    }
+    let hosts_path = hosts_path.ok_or_else(|| SnimapError::Hosts("hosts file not found".into()))?;
+    edit_hosts_at(&hosts_path, hostnames)
+}
+
+fn is_flag_line(line: &str) -> bool {
+    line.starts_with(&HOSTS_FLAG[..15])
+}
+
+/// the hosts file without the block snimap manages
+fn user_lines(hosts: &str) -> Vec<&str> {
+    let lines = hosts.lines().collect::<Vec<&str>>();
+    let flag_lines = (0..lines.len())
+        .filter(|&i| is_flag_line(lines[i]))
+        .collect::<Vec<usize>>();
 
-    let mut hosts_vec = old_hosts
-        .lines()
-        .filter(|line| {
-            let is_flag_line = line.starts_with(&flag[..15]);
-            if is_flag_line {
-                is_will_change = !is_will_change;
-                return false;
-            }
-            !is_will_change
+    // drop everything from the first to the last flag, but when a previous run
+    // left the flags unbalanced only the flags themselves can be dropped safely
+    match flag_lines.as_slice() {
+        [first, .., last] if flag_lines.len() % 2 == 0 => {
+            [&lines[..*first], &lines[last + 1..]].concat()
+        }
+        _ => lines
+            .into_iter()
+            .filter(|line| !is_flag_line(line))
+            .collect(),
+    }
+}
+
+/// entries the user pinned in the hosts file, loopback and `0.0.0.0` would
+/// point back at snimap or nowhere and are left out
+pub fn static_hosts(hosts: &str) -> HashMap<String, IpAddr> {
+    user_lines(hosts)
+        .into_iter()
+        .filter_map(|line| {
+            let mut fields = line.split('#').next()?.split_whitespace();
+            let ip_addr = fields.next()?.parse::<IpAddr>().ok()?;
+            Some(fields.map(move |hostname| (hostname.to_ascii_lowercase(), ip_addr)))
         })
-        .collect::<Vec<&str>>();
+        .flatten()
+        .filter(|(_, ip_addr)| !ip_addr.is_loopback() && !ip_addr.is_unspecified())
+        .collect()
+}
+
+/// `static_hosts` of the system hosts file, empty when it cannot be read
+pub fn read_static_hosts() -> HashMap<String, IpAddr> {
+    hosts_path()
+        .and_then(|path| read_to_string(&path).ok())
+        .map(|hosts| static_hosts(&hosts))
+        .unwrap_or_default()
+}
+
+fn gen_hosts(old_hosts: &str, hostnames: &HashMap<&str, Option<&str>>) -> String {
+    let flag = HOSTS_FLAG;
+
+    let mut hosts_vec = user_lines(old_hosts);
 
     if !hostnames.is_empty() {
         hosts_vec.push(flag);
     }
 
+    // sorted so the managed block stays the same between runs
+    let mut hostnames = hostnames.iter().collect::<Vec<_>>();
+    hostnames.sort_unstable();
+
     let hostpair = hostnames
         .iter()
-        .map(|hostname| format!("127.0.0.1\t{}", hostname))
+        .map(|(hostname, group)| match group {
+            Some(group) => format!("127.0.0.1\t{} # {}", hostname, group),
+            None => format!("127.0.0.1\t{}", hostname),
+        })
         .collect::<Vec<String>>();
 
     hosts_vec.append(
@@ -76,13 +186,161 @@ fn test_gen_hosts() {
 # ...
 127.0.0.1\tlocalhost
 ";
-    let hostnames = vec!["hostname"].into_iter().collect();
+    let hostnames = vec!["hostname", "b.hostname", "a.hostname"]
+        .into_iter()
+        .map(|hostname| (hostname, None))
+        .collect();
     let new_hosts = "# ...
 # ...
 127.0.0.1\tlocalhost
 # Auto Generate by snimap
+127.0.0.1\ta.hostname
+127.0.0.1\tb.hostname
 127.0.0.1\thostname
 # Auto Generate by snimap";
     assert_eq!(gen_hosts(old_hosts, &hostnames), new_hosts);
     assert_eq!(gen_hosts(new_hosts, &hostnames), new_hosts);
 }
+
+#[cfg(test)]
+#[test]
+fn test_gen_hosts_group_label() {
+    let hostnames = HashMap::from([("github.com", Some("Github")), ("hostname", None)]);
+    let new_hosts = "127.0.0.1\tlocalhost
+# Auto Generate by snimap
+127.0.0.1\tgithub.com # Github
+127.0.0.1\thostname
+# Auto Generate by snimap";
+    assert_eq!(gen_hosts("127.0.0.1\tlocalhost", &hostnames), new_hosts);
+    assert_eq!(gen_hosts(new_hosts, &hostnames), new_hosts);
+}
+
+#[cfg(test)]
+#[test]
+fn test_gen_hosts_unbalanced_flags() {
+    let hostnames = HashMap::from([("hostname", None)]);
+    let new_hosts = "127.0.0.1\tlocalhost
+127.0.0.1\tuser
+# Auto Generate by snimap
+127.0.0.1\thostname
+# Auto Generate by snimap";
+
+    let stray = "127.0.0.1\tlocalhost
+# Auto Generate by snimap
+127.0.0.1\tuser";
+    assert_eq!(gen_hosts(stray, &hostnames), new_hosts);
+
+    let nested = "127.0.0.1\tlocalhost
+# Auto Generate by snimap
+127.0.0.1\told
+# Auto Generate by snimap
+# Auto Generate by snimap
+127.0.0.1\told
+# Auto Generate by snimap
+127.0.0.1\tuser";
+    assert_eq!(gen_hosts(nested, &hostnames), new_hosts);
+
+    let restored = "127.0.0.1\tlocalhost
+127.0.0.1\tuser";
+    assert_eq!(gen_hosts(new_hosts, &HashMap::new()), restored);
+}
+
+#[cfg(test)]
+#[test]
+fn test_static_hosts() {
+    let hosts = "127.0.0.1\tlocalhost
+::1\tlocalhost ip6-localhost
+# 1.1.1.1 commented.example
+93.184.216.34  example.com  WWW.Example.com # pinned by hand
+0.0.0.0\tads.example
+2606:2800:220:1::1\texample.net
+not-an-ip\tbroken.example
+# Auto Generate by snimap
+127.0.0.1\tgithub.com # Github
+10.0.0.1\tmanaged.example
+# Auto Generate by snimap
+";
+    let example = "93.184.216.34".parse::<IpAddr>().unwrap();
+    assert_eq!(
+        static_hosts(hosts),
+        HashMap::from([
+            ("example.com".to_string(), example),
+            ("www.example.com".to_string(), example),
+            (
+                "example.net".to_string(),
+                "2606:2800:220:1::1".parse().unwrap()
+            ),
+        ])
+    );
+}
+
+#[cfg(test)]
+#[actix_web::test]
+async fn test_sync_hosts_skipped() {
+    let hosts_path = hosts_path().unwrap();
+    let before = fs::read(&hosts_path).ok();
+    let hostnames = HashMap::from([("snimap.test", None)]);
+    assert!(sync_hosts(&hostnames, true).await.is_ok());
+    assert_eq!(fs::read(&hosts_path).ok(), before);
+}
+
+#[cfg(test)]
+#[test]
+fn test_sync_hosts_restore() {
+    let dir = std::env::temp_dir().join("snimap_test_sync_hosts_restore");
+    fs::create_dir_all(&dir).unwrap();
+    let hosts_path = dir.join("hosts");
+    let user_hosts = "127.0.0.1\tlocalhost";
+    fs::write(&hosts_path, user_hosts).unwrap();
+
+    let hostnames = HashMap::from([("snimap.test", None)]);
+    sync_hosts_at(Some(hosts_path.clone()), &hostnames, false).unwrap();
+    let edited = fs::read_to_string(&hosts_path).unwrap();
+    assert!(edited.contains("snimap.test"), "{edited}");
+
+    // `--no-hosts` leaves the file alone on the way out too
+    sync_hosts_at(Some(hosts_path.clone()), &HashMap::new(), true).unwrap();
+    assert_eq!(fs::read_to_string(&hosts_path).unwrap(), edited);
+
+    sync_hosts_at(Some(hosts_path.clone()), &HashMap::new(), false).unwrap();
+    assert_eq!(fs::read_to_string(&hosts_path).unwrap(), user_hosts);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_edit_hosts_restore_error() {
+    let dir = std::env::temp_dir().join("snimap_test_edit_hosts_restore_error");
+    fs::create_dir_all(&dir).unwrap();
+
+    // fails for root too, unlike a readonly file
+    let hosts_path = dir.join("hosts");
+    fs::write(&hosts_path, b"127.0.0.1\tlocalhost \xff\n").unwrap();
+    assert!(matches!(
+        edit_hosts_at(&hosts_path, &HashMap::new()),
+        Err(SnimapError::Hosts(_))
+    ));
+    assert_eq!(
+        fs::read(&hosts_path).unwrap(),
+        b"127.0.0.1\tlocalhost \xff\n"
+    );
+
+    assert!(matches!(
+        edit_hosts_at(&dir, &HashMap::new()),
+        Err(SnimapError::Hosts(_))
+    ));
+
+    let hosts_path = Path::new("/etc/hosts");
+    assert_eq!(
+        restore_hosts_hint_for(hosts_path, "linux"),
+        "sudo sed -i '/^# Auto Generate by snimap$/,/^# Auto Generate by snimap$/d' /etc/hosts"
+    );
+    assert_eq!(
+        restore_hosts_hint_for(hosts_path, "macos"),
+        "sudo sed -i '' '/^# Auto Generate by snimap$/,/^# Auto Generate by snimap$/d' /etc/hosts"
+    );
+    assert!(restore_hosts_hint_for(hosts_path, "windows").starts_with("open /etc/hosts"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
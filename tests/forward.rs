@@ -0,0 +1,356 @@
+mod support;
+
+use actix_web::{body::to_bytes, http::StatusCode, test, web::Data};
+use snimap::{
+    config::{HeaderRules, Mapping, ResponseRules, UserAgent},
+    handler::{ForwardOptions, PreferredSni},
+    SniMap,
+};
+use support::{proxy_app, proxy_app_with, MockUpstream};
+
+async fn get(snimap: SniMap, upstream: &MockUpstream, host: &str) -> (StatusCode, String) {
+    let request = test::TestRequest::get()
+        .uri("/path?q=1")
+        .insert_header(("host", host));
+    send(snimap, upstream, request).await
+}
+
+async fn send(
+    snimap: SniMap,
+    upstream: &MockUpstream,
+    request: test::TestRequest,
+) -> (StatusCode, String) {
+    let app = test::init_service(proxy_app(snimap, upstream.client_pair())).await;
+    let request = request.to_request();
+    let response = test::call_service(&app, request).await;
+    let status = response.status();
+    let body = to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[actix_web::test]
+async fn forward_disable_sni() {
+    let upstream = MockUpstream::start(&["example.com"]).await;
+    let snimap = SniMap::builder().disable("example.com").build();
+
+    assert_eq!(
+        get(snimap, &upstream, "example.com").await,
+        (StatusCode::OK, "example.com /path?q=1".to_string())
+    );
+    assert_eq!(upstream.snis(), vec![None]);
+
+    upstream.stop().await;
+}
+
+#[actix_web::test]
+async fn forward_override_sni() {
+    let upstream = MockUpstream::start(&["front.example.com"]).await;
+    let snimap = SniMap::builder()
+        .override_sni("example.com", "front.example.com")
+        .build();
+
+    assert_eq!(
+        get(snimap, &upstream, "example.com").await,
+        (StatusCode::OK, "example.com /path?q=1".to_string())
+    );
+    assert_eq!(upstream.snis(), vec![Some("front.example.com".to_string())]);
+
+    upstream.stop().await;
+}
+
+#[actix_web::test]
+async fn forward_user_agent() {
+    let upstream = MockUpstream::start(&["example.com"]).await;
+    let user_agent = |user_agent| {
+        let snimap = Mapping::new("example.com").user_agent(user_agent).into();
+        let request = test::TestRequest::get()
+            .uri("/user-agent")
+            .insert_header(("host", "example.com"))
+            .insert_header(("user-agent", "curl/7.88.1"));
+        send(snimap, &upstream, request)
+    };
+
+    assert_eq!(
+        user_agent(UserAgent::Replace("snimap".to_string())).await,
+        (StatusCode::OK, "snimap".to_string())
+    );
+    assert_eq!(
+        user_agent(UserAgent::Append {
+            append: "snimap".to_string()
+        })
+        .await,
+        (StatusCode::OK, "curl/7.88.1 snimap".to_string())
+    );
+
+    upstream.stop().await;
+}
+
+#[actix_web::test]
+async fn forward_header_rules() {
+    let upstream = MockUpstream::start(&["example.com"]).await;
+    let rules = HeaderRules {
+        remove: Some(vec!["Referer".to_string()]),
+        set: Some([("DNT".to_string(), "1".to_string())].into()),
+        add: Some([("X-Via".to_string(), "snimap".to_string())].into()),
+    };
+    let snimap = Mapping::new("example.com").headers(rules).into();
+    let request = test::TestRequest::get()
+        .uri("/headers")
+        .insert_header(("host", "example.com"))
+        .insert_header(("referer", "https://example.com/"))
+        .insert_header(("dnt", "0"))
+        .insert_header(("x-via", "client"));
+
+    let (status, headers) = send(snimap, &upstream, request).await;
+    assert_eq!(status, StatusCode::OK);
+    let headers = headers.lines().collect::<Vec<_>>();
+    assert!(
+        !headers.iter().any(|h| h.starts_with("referer:")),
+        "{headers:?}"
+    );
+    assert!(headers.contains(&"dnt: 1"), "{headers:?}");
+    assert!(!headers.contains(&"dnt: 0"), "{headers:?}");
+    assert!(headers.contains(&"x-via: client"), "{headers:?}");
+    assert!(headers.contains(&"x-via: snimap"), "{headers:?}");
+
+    upstream.stop().await;
+}
+
+#[actix_web::test]
+async fn forward_forwarded_for() {
+    let upstream = MockUpstream::start(&["example.com"]).await;
+    let options = ForwardOptions {
+        forwarded_for: true,
+        ..Default::default()
+    };
+    let app = test::init_service(proxy_app_with(
+        Mapping::new("example.com").into(),
+        upstream.client_pair(),
+        options,
+    ))
+    .await;
+    let request = test::TestRequest::get()
+        .uri("/headers")
+        .insert_header(("host", "example.com"))
+        .append_header(("x-forwarded-for", "10.0.0.1"))
+        .append_header(("x-forwarded-for", "10.0.0.2"))
+        .append_header(("forwarded", "for=10.0.0.1"))
+        .peer_addr("203.0.113.7:50000".parse().unwrap())
+        .to_request();
+
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body()).await.unwrap();
+    let headers = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .collect::<Vec<_>>();
+    assert!(
+        headers.contains(&"x-forwarded-for: 10.0.0.1, 10.0.0.2, 203.0.113.7"),
+        "{headers:?}"
+    );
+    assert!(
+        headers.contains(&"forwarded: for=10.0.0.1, for=203.0.113.7"),
+        "{headers:?}"
+    );
+
+    upstream.stop().await;
+}
+
+#[actix_web::test]
+async fn forward_rewrite_location() {
+    let upstream = MockUpstream::start(&["front.example.com"]).await;
+    let response_headers = |response| {
+        let snimap = Mapping::new("example.com")
+            .override_sni("front.example.com")
+            .response(response)
+            .into();
+        let app = test::init_service(proxy_app(snimap, upstream.client_pair()));
+        async move {
+            let request = test::TestRequest::get()
+                .uri(concat!(
+                    "/response-headers",
+                    "?Location=https://front.example.com/next?q=1",
+                    "&Strict-Transport-Security=max-age=60",
+                ))
+                .insert_header(("host", "example.com"))
+                .to_request();
+            let response = test::call_service(&app.await, request).await;
+            let header = |name| {
+                let value = response.headers().get(name)?;
+                Some(value.to_str().unwrap().to_string())
+            };
+            (header("location"), header("strict-transport-security"))
+        }
+    };
+
+    assert_eq!(
+        response_headers(ResponseRules::default()).await,
+        (
+            Some("https://example.com/next?q=1".to_string()),
+            Some("max-age=60".to_string())
+        )
+    );
+    assert_eq!(
+        response_headers(ResponseRules {
+            rewrite_location: Some(false),
+            strip_hsts: Some(true),
+        })
+        .await,
+        (Some("https://front.example.com/next?q=1".to_string()), None)
+    );
+
+    upstream.stop().await;
+}
+
+#[actix_web::test]
+async fn forward_follow_redirects() {
+    let upstream = MockUpstream::start(&["example.com", "other.example.com"]).await;
+    let redirect = |follow_redirects, url| {
+        let snimap = SniMap::builder()
+            .remain("example.com")
+            .remain("other.example.com")
+            .remain("dead.example.com")
+            .build();
+        let options = ForwardOptions {
+            follow_redirects,
+            ..Default::default()
+        };
+        let app = test::init_service(proxy_app_with(
+            snimap,
+            upstream.client_pair_unreachable(&["dead.example.com"]),
+            options,
+        ));
+        async move {
+            let request = test::TestRequest::get()
+                .uri(&format!("/redirect-to?url={url}"))
+                .insert_header(("host", "example.com"))
+                .to_request();
+            let response = test::call_service(&app.await, request).await;
+            let status = response.status();
+            let body = to_bytes(response.into_body()).await.unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+
+    assert_eq!(
+        redirect(0, "/next").await,
+        (StatusCode::FOUND, "redirect to /next".to_string())
+    );
+    assert_eq!(
+        redirect(1, "/next").await,
+        (StatusCode::OK, "example.com /next".to_string())
+    );
+    assert_eq!(
+        redirect(1, "https://other.example.com/next").await,
+        (StatusCode::OK, "other.example.com /next".to_string())
+    );
+    assert_eq!(
+        redirect(1, "https://other.example.com:443/next").await,
+        (StatusCode::OK, "other.example.com /next".to_string())
+    );
+    // not in the map, left to the client
+    assert_eq!(
+        redirect(1, "https://unknown.example.com/next").await,
+        (
+            StatusCode::FOUND,
+            "redirect to https://unknown.example.com/next".to_string()
+        )
+    );
+    // the next hop failed, the client gets the redirect it would have followed
+    assert_eq!(
+        redirect(1, "https://dead.example.com/next").await,
+        (
+            StatusCode::FOUND,
+            "redirect to https://dead.example.com/next".to_string()
+        )
+    );
+    // the second hop is over the limit
+    assert_eq!(
+        redirect(1, "/redirect-to?url=/next").await,
+        (StatusCode::FOUND, "redirect to /next".to_string())
+    );
+    assert_eq!(
+        redirect(2, "/redirect-to?url=/next").await,
+        (StatusCode::OK, "example.com /next".to_string())
+    );
+
+    upstream.stop().await;
+}
+
+#[actix_web::test]
+async fn forward_override_list_falls_through() {
+    let upstream = MockUpstream::start(&["front.example.com"]).await;
+    let snimap = SniMap::builder()
+        .override_sni("example.com", ["dead.example.com", "front.example.com"])
+        .build();
+    // replaces the one `proxy_app` registers, so the test can look into it
+    let preferred_sni = Data::new(PreferredSni::from(&snimap));
+    let app = test::init_service(
+        proxy_app(
+            snimap,
+            upstream.client_pair_unreachable(&["dead.example.com"]),
+        )
+        .app_data(preferred_sni.clone()),
+    )
+    .await;
+    let request = || {
+        test::TestRequest::get()
+            .uri("/path?q=1")
+            .insert_header(("host", "example.com"))
+            .to_request()
+    };
+
+    let response = test::call_service(&app, request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        to_bytes(response.into_body()).await.unwrap(),
+        "example.com /path?q=1"
+    );
+    assert_eq!(preferred_sni.candidates("example.com", 2).next(), Some(1));
+
+    // the working front is tried first from now on
+    let response = test::call_service(&app, request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(preferred_sni.candidates("example.com", 2).next(), Some(1));
+    assert!(upstream
+        .snis()
+        .iter()
+        .all(|sni| sni.as_deref() == Some("front.example.com")));
+
+    upstream.stop().await;
+}
+
+#[actix_web::test]
+async fn forward_max_body_size_streamed() {
+    let upstream = MockUpstream::start(&["example.com"]).await;
+    let options = ForwardOptions {
+        max_body_size: Some(1024),
+        ..Default::default()
+    };
+    let app = test::init_service(proxy_app_with(
+        SniMap::from(Mapping::new("example.com")),
+        upstream.client_pair(),
+        options,
+    ))
+    .await;
+    let post = |len| {
+        // no content-length, so the size is only known while streaming
+        test::TestRequest::post()
+            .uri("/path?q=1")
+            .insert_header(("host", "example.com"))
+            .set_payload(vec![b'x'; len])
+            .to_request()
+    };
+
+    assert_eq!(
+        test::call_service(&app, post(1024)).await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        test::call_service(&app, post(2048)).await.status(),
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+
+    upstream.stop().await;
+}
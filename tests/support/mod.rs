@@ -0,0 +1,241 @@
+//! A local https upstream for driving the proxy without network access.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use actix_tls::connect::Resolve;
+use actix_web::{
+    dev::ServerHandle,
+    web::{self, to, Data},
+    App, HttpRequest, HttpResponse, HttpServer,
+};
+use futures::future::{self, LocalBoxFuture};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    Certificate, ClientConfig, RootCertStore, DEFAULT_VERSIONS,
+};
+use snimap::{
+    cert_generate,
+    handler::{ClientOptions, ForwardOptions, ForwardStats, PreferredSni},
+    resolver::UpstreamResolver,
+    reverse_proxy, rustls_server_config, Ca, ClientPair, DisableSni, SniMap,
+};
+
+/// an https server presenting a cert for `hostnames` signed by its own `Ca`,
+/// answering `/user-agent` and `/headers` with the request's user agent and
+/// `"{name}: {value}"` lines, `/response-headers?name=value` with those headers,
+/// `/redirect-to?url=` with a `302` to `url` like httpbin.org, and every other
+/// request with `"{host} {uri}"`
+pub struct MockUpstream {
+    pub addr: SocketAddr,
+    ca: Ca,
+    snis: Arc<Mutex<Vec<Option<String>>>>,
+    handle: ServerHandle,
+}
+
+impl MockUpstream {
+    pub async fn start(hostnames: &[&str]) -> Self {
+        let ca = Ca::new().unwrap();
+        let cert = cert_generate(&hostnames.iter().copied().collect(), &ca)
+            .await
+            .unwrap();
+        let mut server_config = rustls_server_config(cert, DEFAULT_VERSIONS).unwrap();
+        let snis = Arc::new(Mutex::new(Vec::new()));
+        server_config.cert_resolver = Arc::new(RecordSni {
+            inner: server_config.cert_resolver.clone(),
+            snis: snis.clone(),
+        });
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/user-agent",
+                    web::get().to(|request: HttpRequest| async move {
+                        let user_agent = request.headers().get("user-agent");
+                        HttpResponse::Ok().body(user_agent.unwrap().as_bytes().to_vec())
+                    }),
+                )
+                .route(
+                    "/headers",
+                    web::get().to(|request: HttpRequest| async move {
+                        let mut headers = request
+                            .headers()
+                            .iter()
+                            .map(|(name, value)| format!("{name}: {}", value.to_str().unwrap()))
+                            .collect::<Vec<_>>();
+                        headers.sort_unstable();
+                        HttpResponse::Ok().body(headers.join("\n"))
+                    }),
+                )
+                .route(
+                    "/response-headers",
+                    web::get().to(|query: web::Query<Vec<(String, String)>>| async move {
+                        let mut response = HttpResponse::Ok();
+                        for header in query.into_inner() {
+                            response.append_header(header);
+                        }
+                        response.finish()
+                    }),
+                )
+                .route(
+                    "/redirect-to",
+                    web::get().to(|query: web::Query<Vec<(String, String)>>| async move {
+                        let (_, url) = query.iter().find(|(name, _)| name == "url").unwrap();
+                        HttpResponse::Found()
+                            .insert_header(("location", url.as_str()))
+                            .body(format!("redirect to {url}"))
+                    }),
+                )
+                .default_service(to(|request: HttpRequest| async move {
+                    let host = request.headers().get("host").map(|host| host.to_str());
+                    HttpResponse::Ok().body(format!("{} {}", host.unwrap().unwrap(), request.uri()))
+                }))
+        })
+        .workers(1)
+        .bind_rustls("127.0.0.1:0", server_config)
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        Self {
+            addr,
+            ca,
+            snis,
+            handle,
+        }
+    }
+
+    /// trusts this upstream's ca and nothing else
+    pub fn client_config(&self) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(self.ca.der())).unwrap();
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+
+    /// a `ClientPair` sending every host to this upstream
+    pub fn client_pair(&self) -> ClientPair {
+        self.client_pair_unreachable(&[])
+    }
+
+    /// `client_pair` with `unreachable` hosts sent to a port nothing listens on
+    pub fn client_pair_unreachable(&self, unreachable: &[&str]) -> ClientPair {
+        let client_config = self.client_config();
+        ClientPair::new(
+            Arc::new(client_config.clone()),
+            Arc::new(client_config.disable_sni()),
+            FixedResolver {
+                addr: self.addr,
+                unreachable: unreachable.iter().map(|host| host.to_string()).collect(),
+            },
+            ClientOptions::default(),
+        )
+    }
+
+    /// the sni of every handshake so far, `None` where none was sent
+    pub fn snis(&self) -> Vec<Option<String>> {
+        self.snis.lock().unwrap().clone()
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(false).await;
+    }
+}
+
+/// the app `main` serves, minus the admin routes
+pub fn proxy_app(
+    snimap: SniMap,
+    client_pair: ClientPair,
+) -> App<
+    impl actix_web::dev::ServiceFactory<
+        actix_web::dev::ServiceRequest,
+        Config = (),
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    proxy_app_with(snimap, client_pair, ForwardOptions::default())
+}
+
+/// `proxy_app` with `options` in place of the defaults
+pub fn proxy_app_with(
+    snimap: SniMap,
+    client_pair: ClientPair,
+    options: ForwardOptions,
+) -> App<
+    impl actix_web::dev::ServiceFactory<
+        actix_web::dev::ServiceRequest,
+        Config = (),
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .app_data(Data::new(ForwardStats::from(&snimap)))
+        .app_data(Data::new(PreferredSni::from(&snimap)))
+        .app_data(Data::new(snimap))
+        .app_data(Data::new(client_pair))
+        .app_data(Data::new(options))
+        .default_service(to(reverse_proxy))
+}
+
+struct RecordSni {
+    inner: Arc<dyn ResolvesServerCert>,
+    snis: Arc<Mutex<Vec<Option<String>>>>,
+}
+
+impl ResolvesServerCert for RecordSni {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let sni = client_hello.server_name().map(String::from);
+        self.snis.lock().unwrap().push(sni);
+        self.inner.resolve(client_hello)
+    }
+}
+
+/// resolves every host to `addr`, `unreachable` ones to a closed port
+#[derive(Clone)]
+pub struct FixedResolver {
+    pub addr: SocketAddr,
+    pub unreachable: Vec<String>,
+}
+
+impl FixedResolver {
+    fn resolve(&self, host: &str) -> SocketAddr {
+        if self
+            .unreachable
+            .iter()
+            .any(|unreachable| unreachable == host)
+        {
+            SocketAddr::from(([127, 0, 0, 1], 1))
+        } else {
+            self.addr
+        }
+    }
+}
+
+impl Resolve for FixedResolver {
+    fn lookup<'a>(
+        &'a self,
+        host: &'a str,
+        _port: u16,
+    ) -> LocalBoxFuture<'a, Result<Vec<SocketAddr>, Box<dyn std::error::Error>>> {
+        Box::pin(future::ok(vec![self.resolve(host)]))
+    }
+}
+
+impl UpstreamResolver for FixedResolver {
+    fn cached(&self, host: &str) -> Option<SocketAddr> {
+        Some(self.resolve(host))
+    }
+
+    fn invalidate(&self, _host: &str) -> bool {
+        false
+    }
+}